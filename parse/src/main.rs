@@ -6,6 +6,8 @@ use chrono::prelude::*;
 use reqwest;
 use serde;
 
+const MUUS_BASE_URL: &str = "https://muusikoiden.net";
+
 #[derive(Clone)]
 pub(crate) struct MuusSearch {
     pub uniq_id: u64,
@@ -30,7 +32,7 @@ impl fmt::Display for MuusListing {
     }
 }
 
-fn page_to_listings(document: Document) -> Vec<MuusListing> {
+fn page_to_listings(document: Document, base_url: &str) -> Vec<MuusListing> {
     let mut listings: Vec<MuusListing> = Vec::new();
 
     for node in document.find(Attr("class", "bg2")) {
@@ -40,7 +42,7 @@ fn page_to_listings(document: Document) -> Vec<MuusListing> {
             let t = title.find(Attr("href", ())).next().unwrap().html();
             let re = Regex::new(r#"href="([^"]+)""#).unwrap();
             let p = re.captures(&t).unwrap().get(1).unwrap().as_str();  // 0th is the matched string
-            let l_url = format!("https://muusikoiden.net{p}");
+            let l_url = format!("{base_url}{p}");
 
             let t = node.find(Attr("title", ())).next().unwrap().html();
             let re = Regex::new(r#"Lisätty: (\d{2}).(\d{2}).(\d{4}) (\d{2}):(\d{2})"#).unwrap();
@@ -77,19 +79,19 @@ fn page_to_listings(document: Document) -> Vec<MuusListing> {
     return listings;
 }
 
-fn search_muus_market() {
+fn search_muus_market(base_url: &str) {
     let mut listings: Vec<MuusListing> = Vec::new();
 
     // First page of the results
     let client = reqwest::blocking::Client::new();
     let query_init = [("keyword", "elektron".to_string())];
-    let resp: String = client.post("https://muusikoiden.net/tori/haku.php")
+    let resp: String = client.post(format!("{base_url}/tori/haku.php"))
         .query(&query_init).send().unwrap()  // TODO: should handle potential errors here better
         .text().unwrap();
 
     let mut resp_str = resp.to_string();
     let mut page = Document::from(resp_str.as_str());
-    listings.extend(page_to_listings(page.clone()).into_iter());
+    listings.extend(page_to_listings(page.clone(), base_url).into_iter());
 
     // Additional pages
     loop {
@@ -112,13 +114,13 @@ fn search_muus_market() {
             // println!("Parsed:");
             // for (k, v) in &query_cont { println!("{} {}", k.to_string(), v); }
 
-            let resp: String = client.post("https://muusikoiden.net/tori/haku.php")
+            let resp: String = client.post(format!("{base_url}/tori/haku.php"))
                 .query(&query_cont).send().unwrap()
                 .text().unwrap();
             // println!("{resp:?}");
             resp_str = resp.to_string();
             page = Document::from(resp_str.as_str());
-            listings.extend(page_to_listings(page.clone()).into_iter());
+            listings.extend(page_to_listings(page.clone(), base_url).into_iter());
         } else {
             break
         };
@@ -129,6 +131,8 @@ fn search_muus_market() {
 }
 
 fn main() {
-    search_muus_market();
+    // The marketplace can be overridden with the first argument, e.g. a local mock server
+    let base_url = std::env::args().nth(1).unwrap_or(MUUS_BASE_URL.to_string());
+    search_muus_market(base_url.trim_end_matches('/'));
     println!("Hello, world!");
 }
\ No newline at end of file
@@ -0,0 +1,439 @@
+use core::time::Duration;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::muus::MuusClientSettings;
+
+
+const PATH_CONFIG: &str = "./config.toml";  // read if present, unless another file is given
+const ENV_PREFIX: &str = "MUUS_";  // of the environment variables, e.g. MUUS_BOT_TOKEN for `bot_token`
+const MAX_RETRIES_MAX: u32 = 10;  // the backoff doubles with each retry, so more would wait for days
+
+/// Backend of the persistent state.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StoreKind {
+    Json,
+    Sqlite,
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(StoreKind::Json),
+            "sqlite" => Ok(StoreKind::Sqlite),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the bot receives the messages of its users.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UpdateMode {
+    Polling,
+    Webhook,
+}
+
+impl std::str::FromStr for UpdateMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "polling" => Ok(UpdateMode::Polling),
+            "webhook" => Ok(UpdateMode::Webhook),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Runtime configuration of the bot.
+///
+/// Loaded from the defaults, a TOML file, the environment variables and the command-line flags,
+/// each overriding the previous. The same key is `bot_token` in the file, `MUUS_BOT_TOKEN`
+/// in the environment and `--bot-token` on the command line.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub bot_token: String,  // obtained from @BotFather
+    pub poll_interval: u64,  // in seconds, how freq-ly to poll user input from the bot
+    pub mode: UpdateMode,  // poll Telegram for updates, or receive them on a webhook
+    pub webhook_addr: String,  // local address of the webhook server, e.g. behind a reverse proxy
+    pub webhook_path: String,  // of the requests to accept, the rest are refused
+    pub webhook_url: String,  // public URL to register with Telegram on start, empty to register it by hand
+    pub webhook_secret: String,  // expected in the secret token header of every request
+    pub monitor_interval: u64,  // in seconds, how freq-ly to monitor the query database
+    pub cooldown_interval: u64,  // in seconds, default period between same query executions
+    pub interval_min: u64,  // in seconds, shortest custom period users may set for a query
+    pub interval_max: u64,  // in seconds, longest custom period users may set for a query
+    pub store: StoreKind,
+    pub db_path: PathBuf,  // JSON file or SQLite database, depending on `store`
+    pub db_backups: usize,  // how many previous versions of the db to keep next to it, at most one per hour
+    pub base_url: String,  // marketplace to search, e.g. a local mock server for staging
+    pub user_agent: String,
+    pub requests_per_sec: f64,  // max rate of requests to the marketplace, shared by all queries
+    pub timeout: u64,  // in seconds, for a single request to the marketplace
+    pub max_retries: u32,  // on connection errors and 5xx/429 responses
+    pub retry_backoff: u64,  // in seconds, before the first retry, doubled for each next one
+    pub max_pages: usize,  // of results to fetch per query run, the rest are older anyway
+    #[serde(skip)]
+    pub path: Option<PathBuf>,  // of the config file loaded, None if there was none
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bot_token: String::new(),
+            poll_interval: 5,
+            mode: UpdateMode::Polling,
+            webhook_addr: "127.0.0.1:8443".to_string(),
+            webhook_path: "/telegram".to_string(),
+            webhook_url: String::new(),
+            webhook_secret: String::new(),
+            monitor_interval: 60,
+            cooldown_interval: 3600,
+            interval_min: 900,
+            interval_max: 604800,
+            store: StoreKind::Json,
+            db_path: PathBuf::from("./db.json"),
+            db_backups: 3,
+            base_url: "https://muusikoiden.net".to_string(),
+            user_agent: "muus_watcher_bot/0.1 (+https://github.com/soupault/muus_watcher_bot)".to_string(),
+            requests_per_sec: 0.5,
+            timeout: 30,
+            max_retries: 3,
+            retry_backoff: 5,
+            max_pages: 10,
+            path: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    /// The config file could not be read.
+    Io { path: PathBuf, err: std::io::Error },
+    /// The config file is not valid TOML or has unknown keys.
+    File { path: PathBuf, err: toml::de::Error },
+    /// A value from the environment or the command line could not be parsed.
+    Value { source: String, value: String },
+    /// An unknown or incomplete command-line flag.
+    Args(String),
+    /// The configuration is complete but not usable.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, err } => write!(f, "cannot read config file {}: {err}", path.display()),
+            ConfigError::File { path, err } => write!(f, "invalid config file {}: {err}", path.display()),
+            ConfigError::Value { source, value } => write!(f, "invalid value of {source}: {value:?}"),
+            ConfigError::Args(reason) => write!(f, "{reason}"),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {reason}"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { err, .. } => Some(err),
+            ConfigError::File { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Keys of the fields that can be set from the environment and the command line.
+const KEYS: [&str; 21] = ["bot_token", "poll_interval", "mode", "webhook_addr", "webhook_path", "webhook_url",
+                          "webhook_secret", "monitor_interval", "cooldown_interval",
+                          "interval_min", "interval_max", "store", "db_path", "db_backups", "base_url", "user_agent",
+                          "requests_per_sec", "timeout", "max_retries", "retry_backoff", "max_pages"];
+
+impl Config {
+    /// Loads the configuration of the process from its environment and `args`, see [`Config::from_sources`].
+    pub fn load(args: &[String]) -> Result<Config, ConfigError> {
+        Config::from_sources(args, |name| std::env::var(name).ok())
+    }
+
+    /// Loads the configuration from the config file, `env` lookups and command-line `args`.
+    ///
+    /// The file is `--config <path>` or `MUUS_CONFIG`, falling back to `./config.toml` if it exists.
+    pub fn from_sources(args: &[String], env: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let flags = parse_args(args)?;
+
+        let path_arg = flags.iter().find(|(k, _)| k == "config").map(|(_, v)| PathBuf::from(v));
+        let path_env = env(&format!("{ENV_PREFIX}CONFIG")).map(PathBuf::from);
+        let mut config = match path_arg.or(path_env) {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(PATH_CONFIG).exists() => Config::from_file(Path::new(PATH_CONFIG))?,
+            None => Config::default(),
+        };
+
+        for key in KEYS {
+            let name = format!("{ENV_PREFIX}{}", key.to_uppercase());
+            if let Some(value) = env(&name) {
+                config.set(key, &value).map_err(|_| ConfigError::Value { source: name, value })?;
+            }
+        }
+        for (key, value) in flags.iter().filter(|(k, _)| k != "config") {
+            config.set(key, value).map_err(|_| ConfigError::Value {
+                source: format!("--{}", key.replace('_', "-")), value: value.clone() })?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io { path: path.to_path_buf(), err })?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|err| ConfigError::File { path: path.to_path_buf(), err })?;
+        Ok(Config { path: Some(path.to_path_buf()), ..config })
+    }
+
+    /// Sets the field with the given key from its text value.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ()> {
+        fn parse<T: std::str::FromStr>(value: &str) -> Result<T, ()> {
+            value.trim().parse().map_err(|_| ())
+        }
+        match key {
+            "bot_token" => self.bot_token = value.trim().to_string(),
+            "poll_interval" => self.poll_interval = parse(value)?,
+            "mode" => self.mode = parse(value)?,
+            "webhook_addr" => self.webhook_addr = value.trim().to_string(),
+            "webhook_path" => self.webhook_path = value.trim().to_string(),
+            "webhook_url" => self.webhook_url = value.trim().to_string(),
+            "webhook_secret" => self.webhook_secret = value.trim().to_string(),
+            "monitor_interval" => self.monitor_interval = parse(value)?,
+            "cooldown_interval" => self.cooldown_interval = parse(value)?,
+            "interval_min" => self.interval_min = parse(value)?,
+            "interval_max" => self.interval_max = parse(value)?,
+            "store" => self.store = parse(value)?,
+            "db_path" => self.db_path = PathBuf::from(value),
+            "db_backups" => self.db_backups = parse(value)?,
+            "base_url" => self.base_url = value.trim().to_string(),
+            "user_agent" => self.user_agent = value.to_string(),
+            "requests_per_sec" => self.requests_per_sec = parse(value)?,
+            "timeout" => self.timeout = parse(value)?,
+            "max_retries" => self.max_retries = parse(value)?,
+            "retry_backoff" => self.retry_backoff = parse(value)?,
+            "max_pages" => self.max_pages = parse(value)?,
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    /// Checks that the bot can connect to Telegram, not needed by the maintenance commands.
+    pub fn check_token(&self) -> Result<(), ConfigError> {
+        if self.bot_token.is_empty() {
+            let path = self.path.as_deref().unwrap_or(Path::new(PATH_CONFIG));
+            return Err(ConfigError::Invalid(format!(
+                "the bot token is missing, set `bot_token` in {}, \
+                 the {ENV_PREFIX}BOT_TOKEN environment variable, or the --bot-token flag", path.display())));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (key, value) in [("poll_interval", self.poll_interval), ("monitor_interval", self.monitor_interval),
+                             ("cooldown_interval", self.cooldown_interval), ("interval_min", self.interval_min),
+                             ("timeout", self.timeout), ("max_pages", self.max_pages as u64)] {
+            if value == 0 {
+                return Err(ConfigError::Invalid(format!("`{key}` must be positive")));
+            }
+        }
+        if self.max_retries > MAX_RETRIES_MAX {
+            return Err(ConfigError::Invalid(format!("`max_retries` must be at most {MAX_RETRIES_MAX}")));
+        }
+        if self.interval_min > self.interval_max {
+            return Err(ConfigError::Invalid("`interval_min` is greater than `interval_max`".to_string()));
+        }
+        if !(self.requests_per_sec > 0.0 && self.requests_per_sec.is_finite()) {
+            return Err(ConfigError::Invalid("`requests_per_sec` must be positive".to_string()));
+        }
+        match url::Url::parse(&self.base_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => return Err(ConfigError::Invalid(format!("`base_url` is not an HTTP URL: {}", self.base_url))),
+        }
+        if self.mode == UpdateMode::Webhook {
+            self.validate_webhook()?;
+        }
+        Ok(())
+    }
+
+    fn validate_webhook(&self) -> Result<(), ConfigError> {
+        if self.webhook_addr.parse::<std::net::SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid(format!("`webhook_addr` is not an IP address and port: {}",
+                                                    self.webhook_addr)));
+        }
+        if !self.webhook_path.starts_with('/') {
+            return Err(ConfigError::Invalid(format!("`webhook_path` must start with \"/\": {}", self.webhook_path)));
+        }
+        // The characters and length allowed by Telegram
+        let secret_ok = (1..=256).contains(&self.webhook_secret.len())
+            && self.webhook_secret.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !secret_ok {
+            return Err(ConfigError::Invalid(
+                "`webhook_secret` must be 1-256 characters of A-Z, a-z, 0-9, _ and -".to_string()));
+        }
+        if !self.webhook_url.is_empty() {
+            match url::Url::parse(&self.webhook_url) {
+                Ok(url) if url.scheme() == "https" => {}
+                _ => return Err(ConfigError::Invalid(format!("`webhook_url` is not an HTTPS URL: {}",
+                                                             self.webhook_url))),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn muus_settings(&self) -> MuusClientSettings {
+        MuusClientSettings {
+            user_agent: self.user_agent.clone(),
+            timeout: Duration::from_secs(self.timeout),
+            requests_per_sec: self.requests_per_sec,
+            max_retries: self.max_retries,
+            backoff: Duration::from_secs(self.retry_backoff),
+            max_pages: self.max_pages,
+        }
+    }
+}
+
+/// Splits "--key value" and "--key=value" flags into (key, value) pairs, with dashes in keys as underscores.
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::Args(format!("unexpected argument: {arg}")));
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key, value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag, value.clone()),
+                None => return Err(ConfigError::Args(format!("missing value of --{flag}"))),
+            },
+        };
+        let key = key.replace('-', "_");
+        if key != "config" && !KEYS.contains(&key.as_str()) {
+            return Err(ConfigError::Args(format!("unknown flag: --{}", key.replace('_', "-"))));
+        }
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::testutil::temp_dir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn load(args: &[String], env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Config::from_sources(args, |name| env.get(name).cloned())
+    }
+
+    fn write_config(name: &str, text: &str) -> PathBuf {
+        let path = temp_dir(&format!("config_{name}"), true).join("config.toml");
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn missing_token() {
+        let err = load(&args(&["--config", "/nonexistent/config.toml"]), &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::Io { .. }));
+
+        let path = write_config("missing_token", "poll_interval = 10\n");
+        let config = load(&args(&["--config", path.to_str().unwrap()]), &[]).unwrap();
+        let err = config.check_token().unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+        assert!(err.to_string().contains("MUUS_BOT_TOKEN"));
+        // Points to the file actually loaded
+        assert!(err.to_string().contains(path.to_str().unwrap()), "{err}");
+        assert!(Config::default().check_token().unwrap_err().to_string().contains(PATH_CONFIG));
+    }
+
+    #[test]
+    fn precedence() {
+        let path = write_config("precedence",
+                                "bot_token = \"file\"\ncooldown_interval = 1800\nmax_pages = 3\ntimeout = 10\n");
+        let config_arg = format!("--config={}", path.display());
+
+        let config = load(&args(&[&config_arg]), &[]).unwrap();
+        assert_eq!(config.bot_token, "file");
+        assert_eq!(config.cooldown_interval, 1800);
+        assert_eq!(config.poll_interval, Config::default().poll_interval);
+
+        let env = [("MUUS_BOT_TOKEN", "env"), ("MUUS_MAX_PAGES", "5")];
+        let config = load(&args(&[&config_arg, "--max-pages", "7"]), &env).unwrap();
+        assert_eq!(config.bot_token, "env");
+        assert_eq!(config.max_pages, 7);
+        assert_eq!(config.timeout, 10);
+
+        let env = [("MUUS_CONFIG", path.to_str().unwrap())];
+        let config = load(&args(&["--bot-token=cli"]), &env).unwrap();
+        assert_eq!(config.bot_token, "cli");
+        assert_eq!(config.cooldown_interval, 1800);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let token = ("MUUS_BOT_TOKEN", "token");
+        let path = write_config("invalid_values", "bot_token = \"x\"\nunknown_key = 1\n");
+        let err = load(&args(&["--config", path.to_str().unwrap()]), &[]).unwrap_err();
+        assert!(matches!(err, ConfigError::File { .. }));
+
+        let err = load(&args(&["--config", "/dev/null", "--timeout=soon"]), &[token]).unwrap_err();
+        assert!(matches!(err, ConfigError::Value { .. }));
+        let err = load(&args(&["--config", "/dev/null"]), &[token, ("MUUS_REQUESTS_PER_SEC", "0")]).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+        let err = load(&args(&["--config", "/dev/null", "--interval-min=7200", "--interval-max=3600"]),
+                       &[token]).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+        let err = load(&args(&["--config", "/dev/null", "--base-url=muusikoiden.net"]), &[token]).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+        let err = load(&args(&["--config", "/dev/null", "--max-retries=32"]), &[token]).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+        let err = load(&args(&["--config", "/dev/null", "--frobnicate=1"]), &[token]).unwrap_err();
+        assert!(matches!(err, ConfigError::Args(_)));
+        let err = load(&args(&["--config", "/dev/null", "--timeout"]), &[token]).unwrap_err();
+        assert!(matches!(err, ConfigError::Args(_)));
+    }
+
+    #[test]
+    fn webhook() {
+        let token = ("MUUS_BOT_TOKEN", "token");
+        // Not checked while polling
+        let config = load(&args(&["--config", "/dev/null"]), &[token]).unwrap();
+        assert_eq!(config.mode, UpdateMode::Polling);
+
+        let err = load(&args(&["--config", "/dev/null", "--mode=webhook"]), &[token]).unwrap_err();
+        assert!(err.to_string().contains("webhook_secret"), "{err}");
+        let err = load(&args(&["--config", "/dev/null", "--mode=webhook", "--webhook-secret=a b"]), &[token])
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+        let err = load(&args(&["--config", "/dev/null", "--mode=webhook", "--webhook-secret=s3cret",
+                               "--webhook-url=http://bot.example.com/telegram"]), &[token]).unwrap_err();
+        assert!(err.to_string().contains("webhook_url"), "{err}");
+
+        let env = [token, ("MUUS_MODE", "Webhook"), ("MUUS_WEBHOOK_SECRET", "s3cret")];
+        let config = load(&args(&["--config", "/dev/null", "--webhook-addr=0.0.0.0:8080",
+                                  "--webhook-url=https://bot.example.com/telegram"]), &env).unwrap();
+        assert_eq!(config.mode, UpdateMode::Webhook);
+        assert_eq!(config.webhook_addr, "0.0.0.0:8080");
+        assert_eq!(config.webhook_path, "/telegram");
+    }
+}
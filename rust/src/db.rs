@@ -0,0 +1,172 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+
+#[derive(Debug)]
+pub(crate) enum DbError {
+    Io { path: PathBuf, err: io::Error },
+    Corrupt { path: PathBuf, err: serde_json::Error },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Io { path, err } => write!(f, "{}: {err}", path.display()),
+            DbError::Corrupt { path, err } => write!(f, "{} is corrupt: {err}", path.display()),
+        }
+    }
+}
+
+impl Error for DbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DbError::Io { err, .. } => Some(err),
+            DbError::Corrupt { err, .. } => Some(err),
+        }
+    }
+}
+
+/// Path of the n-th backup of the db, e.g. "db.json.1" for the newest one.
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Writes `value` to `path` as JSON so that a crash never leaves a partially written file.
+///
+/// The data goes to a temp file next to `path`, which is fsynced and renamed over it.
+/// The replaced file is kept as "{path}.1", shifting the older ones up to "{path}.{backups}".
+pub(crate) fn save<T: Serialize>(path: &Path, value: &T, backups: usize) -> Result<(), DbError> {
+    let io_err = |path: &Path| { let path = path.to_path_buf(); move |err| DbError::Io { path, err } };
+    let data = serde_json::to_string_pretty(value)
+        .map_err(|err| DbError::Io { path: path.to_path_buf(), err: err.into() })?;
+
+    let mut path_tmp = path.as_os_str().to_owned();
+    path_tmp.push(".tmp");
+    let path_tmp = PathBuf::from(path_tmp);
+    let mut file = File::create(&path_tmp).map_err(io_err(&path_tmp))?;
+    file.write_all(data.as_bytes()).and_then(|_| file.sync_all()).map_err(io_err(&path_tmp))?;
+    drop(file);
+
+    if backups > 0 && path.exists() {
+        for n in (1..backups).rev() {
+            let from = backup_path(path, n);
+            if from.exists() {
+                fs::rename(&from, backup_path(path, n + 1)).map_err(io_err(&from))?;
+            }
+        }
+        // A hard link keeps the current file in place until the rename below
+        let newest = backup_path(path, 1);
+        fs::hard_link(path, &newest).or_else(|_| fs::copy(path, &newest).map(|_| ()))
+            .map_err(io_err(&newest))?;
+    }
+    fs::rename(&path_tmp, path).map_err(io_err(path))?;
+
+    // Persist the rename itself, not supported on every platform
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(dir) {
+        _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn load_file<T: DeserializeOwned>(path: &Path) -> Result<T, DbError> {
+    let text = fs::read_to_string(path).map_err(|err| DbError::Io { path: path.to_path_buf(), err })?;
+    serde_json::from_str(&text).map_err(|err| DbError::Corrupt { path: path.to_path_buf(), err })
+}
+
+/// Reads the db written by [`save`], `None` if there is none yet.
+///
+/// If the file is missing or corrupt, the newest good backup is used instead, and a corrupt file
+/// is moved aside to "{path}.corrupt-{time}" so that the next save does not rotate it into the backups.
+pub(crate) fn load<T: DeserializeOwned>(path: &Path, backups: usize) -> Result<Option<T>, DbError> {
+    let candidates: Vec<PathBuf> = std::iter::once(path.to_path_buf())
+        .chain((1..=backups).map(|n| backup_path(path, n)))
+        .filter(|p| p.exists())
+        .collect();
+    let mut error = None;
+    for candidate in candidates.iter() {
+        match load_file(candidate) {
+            Ok(value) => {
+                if candidate != path {
+                    println!("Loaded the db from backup {}", candidate.display());
+                    if let Some(DbError::Corrupt { .. }) = error {
+                        let mut path_corrupt = path.as_os_str().to_owned();
+                        path_corrupt.push(format!(".corrupt-{}", Utc::now().format("%Y%m%d%H%M%S")));
+                        _ = fs::rename(path, path_corrupt);
+                    }
+                }
+                return Ok(Some(value));
+            }
+            Err(err) => {
+                println!("Failed to load the db: {err}");
+                if candidate == path {
+                    error = Some(err);
+                }
+            }
+        }
+    }
+    match error {
+        // Nothing to fall back to
+        Some(err) => Err(err),
+        None if candidates.is_empty() => Ok(None),
+        None => Err(DbError::Io { path: path.to_path_buf(),
+                                  err: io::Error::new(io::ErrorKind::NotFound, "no readable backup") }),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn save_and_rotate() {
+        let path = temp_dir("db_rotate", true).join("db.json");
+        assert_eq!(load::<Vec<u32>>(&path, 2).unwrap(), None);
+
+        for n in 0..4u32 {
+            save(&path, &vec![n], 2).unwrap();
+        }
+        assert_eq!(load::<Vec<u32>>(&path, 2).unwrap(), Some(vec![3]));
+        assert_eq!(load_file::<Vec<u32>>(&backup_path(&path, 1)).unwrap(), vec![2]);
+        assert_eq!(load_file::<Vec<u32>>(&backup_path(&path, 2)).unwrap(), vec![1]);
+        assert!(!backup_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn corrupt_falls_back_to_backup() {
+        let dir = temp_dir("db_corrupt", true);
+        let path = dir.join("db.json");
+        save(&path, &vec![1u32], 2).unwrap();
+        save(&path, &vec![2u32], 2).unwrap();
+
+        // Truncated mid-write
+        fs::write(&path, "[2, ").unwrap();
+        assert_eq!(load::<Vec<u32>>(&path, 2).unwrap(), Some(vec![1]));
+        assert!(!path.exists());
+        let moved = fs::read_dir(&dir).unwrap()
+            .any(|e| e.unwrap().file_name().to_string_lossy().starts_with("db.json.corrupt-"));
+        assert!(moved);
+
+        // Lost between the rotation and the rename
+        assert_eq!(load::<Vec<u32>>(&path, 2).unwrap(), Some(vec![1]));
+
+        // Nothing good left
+        fs::write(backup_path(&path, 1), "").unwrap();
+        assert!(load::<Vec<u32>>(&path, 2).is_err());
+        fs::write(&path, "{").unwrap();
+        assert!(matches!(load::<Vec<u32>>(&path, 0), Err(DbError::Corrupt { .. })));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use chrono::prelude::*;
+use serde::Deserialize;
+use crate::muus::{helsinki_to_utc, MuusFilters};
+use crate::store::{DBQuery, DBUser, Store, StoreError, DEFAULT_TIMEZONE};
+
+
+/// User as stored by the Python bot in `db.yaml`, keyed by the Telegram user ID.
+#[derive(Deserialize, Debug)]
+struct PyUser {
+    chat_id: i64,
+    #[serde(default)]
+    qs: Vec<String>,
+    #[serde(default)]
+    dtimes_prev_req: Vec<String>,  // "never" or "dd.mm.yyyy hh:mm" in the server time, one per query
+}
+
+#[derive(Debug)]
+pub(crate) enum ImportError {
+    Yaml(serde_yaml::Error),
+    Store(StoreError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Yaml(err) => write!(f, "invalid db.yaml: {err}"),
+            ImportError::Store(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for ImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImportError::Yaml(err) => Some(err),
+            ImportError::Store(err) => Some(err),
+        }
+    }
+}
+
+impl From<StoreError> for ImportError {
+    fn from(err: StoreError) -> Self { ImportError::Store(err) }
+}
+
+/// Outcome of an import, the conflicts are the entries that were skipped or changed.
+#[derive(Default, Debug)]
+pub(crate) struct ImportReport {
+    pub users_added: usize,
+    pub users_merged: usize,  // already had an account, only their new queries were added
+    pub queries_added: usize,
+    pub conflicts: Vec<String>,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Imported {} queries: {} new users, {} merged into existing accounts, {} conflicts",
+               self.queries_added, self.users_added, self.users_merged, self.conflicts.len())?;
+        for conflict in self.conflicts.iter() {
+            write!(f, "\n  {conflict}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a time of the Python bot, `None` for "never".
+fn parse_py_dtime(text: &str) -> Result<Option<DateTime<Utc>>, ()> {
+    if text == "never" {
+        return Ok(None);
+    }
+    // The Python bot ran on a Finnish server with `datetime.now()`
+    let naive = NaiveDateTime::parse_from_str(text.trim(), "%d.%m.%Y %H:%M").map_err(|_| ())?;
+    helsinki_to_utc(naive).map(Some).ok_or(())
+}
+
+/// Adds the users and queries of the Python bot's `db.yaml` to the store.
+///
+/// Users already in the store keep their account, and only the queries they do not have yet are added.
+/// The queries get the first free `uniq_id`s, the same way as with "/add".
+pub(crate) fn import_python_db(store: &mut dyn Store, yaml: &str) -> Result<ImportReport, ImportError> {
+    let db: serde_yaml::Mapping = serde_yaml::from_str::<Option<serde_yaml::Mapping>>(yaml)
+        .map_err(ImportError::Yaml)?
+        .unwrap_or_default();
+    let mut report = ImportReport::default();
+
+    for (key, value) in db {
+        let user_id = match &key {
+            serde_yaml::Value::Number(n) => n.to_string(),
+            serde_yaml::Value::String(s) => s.clone(),
+            _ => {
+                report.conflicts.push(format!("user {key:?}: not a user ID, skipped"));
+                continue;
+            }
+        };
+        let py_user: PyUser = match serde_yaml::from_value(value) {
+            Ok(py_user) => py_user,
+            Err(err) => {
+                report.conflicts.push(format!("user {user_id}: {err}, skipped"));
+                continue;
+            }
+        };
+        if py_user.qs.len() != py_user.dtimes_prev_req.len() {
+            report.conflicts.push(format!("user {user_id}: {} queries but {} times, the missing ones are \"never\"",
+                                          py_user.qs.len(), py_user.dtimes_prev_req.len()));
+        }
+
+        let db_user = match store.user(py_user.chat_id)? {
+            Some(db_user) => {
+                report.users_merged += 1;
+                report.conflicts.push(format!("user {user_id}: chat {} already has an account (user {}), \
+                                               adding the queries to it", py_user.chat_id, db_user.user_id));
+                db_user
+            }
+            None => {
+                let db_user = DBUser {
+                    chat_id: py_user.chat_id,
+                    user_id: user_id.clone(),
+                    queries: Vec::new(),
+                    timezone: DEFAULT_TIMEZONE,
+                };
+                store.put_user(&db_user)?;
+                report.users_added += 1;
+                db_user
+            }
+        };
+
+        let mut qs_ids: HashSet<u64> = db_user.queries.iter().map(|q| q.uniq_id).collect();
+        let mut qs_texts: HashSet<String> = db_user.queries.iter()
+            .filter(|q| q.filters == MuusFilters::default())
+            .map(|q| q.text.clone())
+            .collect();
+        for (pos, text) in py_user.qs.iter().enumerate() {
+            if text.is_empty() {
+                report.conflicts.push(format!("user {user_id}: empty query, skipped"));
+                continue;
+            }
+            if !qs_texts.insert(text.clone()) {
+                report.conflicts.push(format!("user {user_id}: query \"{text}\" already exists, skipped"));
+                continue;
+            }
+            let dtime_text = py_user.dtimes_prev_req.get(pos).map_or("never", |d| d.as_str());
+            let dtime_prev_req = parse_py_dtime(dtime_text).unwrap_or_else(|_| {
+                report.conflicts.push(format!("user {user_id}: query \"{text}\" has an invalid time \"{dtime_text}\", \
+                                               its first run will set a new baseline"));
+                None
+            });
+
+            let idx = (0..).find(|i| !qs_ids.contains(i)).unwrap();
+            qs_ids.insert(idx);
+            let dbq = DBQuery { dtime_prev_req, imported: true, ..DBQuery::new(idx, text) };
+            store.put_query(py_user.chat_id, &dbq)?;
+            report.queries_added += 1;
+        }
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::store::JsonStore;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn python_db() {
+        let dir = temp_dir("import", true);
+        let mut store = JsonStore::open(&dir.join("db.json"), 0).unwrap();
+
+        // Already migrated by hand
+        let existing = DBUser { chat_id: 222, user_id: "bob".to_string(), queries: Vec::new(), timezone: DEFAULT_TIMEZONE };
+        store.put_user(&existing).unwrap();
+        store.put_query(222, &DBQuery::new(0, "moog+sub+37")).unwrap();
+
+        let yaml = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/python_db.yaml")).unwrap();
+        let report = import_python_db(&mut store, &yaml).unwrap();
+        assert_eq!((report.users_added, report.users_merged, report.queries_added), (1, 1, 4));
+        // Merged user, existing query, invalid time, duplicate query
+        assert_eq!(report.conflicts.len(), 4, "{report}");
+
+        let users = store.users().unwrap();
+        let alice = users.iter().find(|u| u.chat_id == 111).unwrap();
+        assert_eq!(alice.user_id, "123456");
+        let summary: Vec<(u64, &str, Option<DateTime<Utc>>)> = alice.queries.iter()
+            .map(|q| (q.uniq_id, q.text.as_str(), q.dtime_prev_req)).collect();
+        assert_eq!(summary, vec![
+            (0, "arturia+keylab+-88", None),
+            // Summer time, UTC+3
+            (1, "elektron+digitakt", Some(Utc.with_ymd_and_hms(2024, 6, 1, 9, 30, 0).unwrap())),
+            (2, "rhodes", None),
+        ]);
+
+        let bob = users.iter().find(|u| u.chat_id == 222).unwrap();
+        assert_eq!(bob.user_id, "bob");
+        let summary: Vec<(u64, &str, Option<DateTime<Utc>>)> = bob.queries.iter()
+            .map(|q| (q.uniq_id, q.text.as_str(), q.dtime_prev_req)).collect();
+        assert_eq!(summary, vec![
+            (0, "moog+sub+37", None),
+            // Winter time, UTC+2
+            (1, "nord+stage", Some(Utc.with_ymd_and_hms(2024, 1, 15, 18, 5, 0).unwrap())),
+        ]);
+        // Only the imported queries get the rule of the Python bot on their first run
+        let imported: Vec<bool> = bob.queries.iter().map(|q| q.imported).collect();
+        assert_eq!(imported, vec![false, true]);
+
+        assert!(matches!(import_python_db(&mut store, "- not a mapping"), Err(ImportError::Yaml(_))));
+        assert_eq!(import_python_db(&mut store, "").unwrap().queries_added, 0);
+    }
+}
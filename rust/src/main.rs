@@ -1,317 +1,1331 @@
+mod config;
+mod db;
+mod import;
+mod messenger;
 mod muus;
+mod sqlite;
+mod store;
+#[cfg(test)]
+mod testutil;
+mod webhook;
 
 use core::time::Duration;
 use std::{fs, thread};
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex};
-use std::path::Path;
+use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, PoisonError};
 use chrono::prelude::*;
-use frankenstein::{ChatId, Message, Api, UpdateContent, GetUpdatesParams, ReplyParameters,
-                   SendMessageParams, TelegramApi};
+use chrono_tz::Tz;
+use frankenstein::{ChatId, Message, Api, Update, UpdateContent, GetUpdatesParams, ReplyParameters,
+                   SendMessageParams, TelegramApi, CallbackQuery, AnswerCallbackQueryParams,
+                   InlineKeyboardButton, InlineKeyboardMarkup, MaybeInaccessibleMessage, ReplyMarkup,
+                   SetWebhookParams, DeleteWebhookParams};
 use frankenstein::ParseMode::Html;
-use serde::{Deserialize, Serialize};
-use serde_json;
-use crate::muus::{MuusSearch, search_muus_market};
+use crate::config::{Config, UpdateMode};
+use crate::messenger::Messenger;
+use crate::store::{open_store, DBNotification, DBQuery, DBUser, ListingNews, Store, StoreError,
+                   DEFAULT_TIMEZONE};
+use crate::webhook::WebhookServer;
+use crate::muus::{group_searches, MuusClient, MuusFilters, MuusListing, MuusListingKind, MuusLocalFilter, MuusListingDetail, MuusSearch};
 
 
-const TG_BOT_TOKEN: &str = "";  // ACT: set with the bot token obtained from @BotFather
-
-const TG_POLL_INTERVAL: u64 = 5;  // in seconds, how freq-ly to poll user input from the bot
-const QUERY_MONITOR_INTERVAL: u64 = 60;  // in seconds, how freq-ly to monitor the query database
-const QUERY_COOLDOWN_INTERVAL: u64 = 3600;  // in seconds, period between same query executions
-const PATH_DB: &str = "./db.json";
+const EXCERPT_LENGTH: usize = 200;  // in chars, how much of the description to include in notifications
+const CALLBACK_DETAIL: &str = "detail:";  // prefix of the "Details" button data, followed by the listing path
+const HISTORY_LENGTH: usize = 10;  // how many latest notifications "/history" shows
 
 
 #[derive(Debug)]
 enum TgCommand {
     Help,
     Start,
-    Add(String),
+    Add(String, MuusFilters),
     List,
     Remove(Vec<u64>),
+    Edits(u64, bool),
+    Pause(u64, bool),  // false to resume
+    Interval(u64, Option<u64>),  // None to restore the default
+    Filter(u64, Option<MuusLocalFilter>),  // None to show the current one
+    Show(String),
+    Timezone(Option<String>),
+    History,
     Clear,
     Stop,
+    Invalid(String),
     Unknown(()),
 }
 
-type DBRoot = Vec<DBUser>;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct DBUser {
-    chat_id: i64,
-    user_id: String,
-    queries: Vec<DBQuery>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct DBQuery {
-    uniq_id: u64,
-    text: String,
-    dtime_prev_req: DateTime<Utc>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
 struct SharedState {
-    db: DBRoot,
+    store: Box<dyn Store>,
 }
 
 fn main() {
-    let db: Vec<DBUser>;
-    if Path::new(PATH_DB).exists() {
-        let fc = fs::read_to_string(PATH_DB).unwrap();
-        db = serde_json::from_str(&fc).unwrap();
-    } else {
-        db = serde_json::from_str(r#"[]"#).unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "import") {
+        if let Err(err) = run_import(&args[1..]) {
+            eprintln!("Failed to import: {err}");
+            std::process::exit(1);
+        }
+        return;
     }
 
-    let state = SharedState { db };
+    let config = match Config::load(&args).and_then(|config| config.check_token().map(|_| config)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to load the configuration: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let store = match open_store(&config) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("Failed to open the db, restore it from a backup manually: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let state = SharedState { store };
     let state_shared = Arc::new(Mutex::new(state));
     let state_search = Arc::clone(&state_shared);
     let state_repl = Arc::clone(&state_shared);
 
-    let api_shared = Arc::new(Api::new(TG_BOT_TOKEN));
+    let api_shared = Arc::new(Api::new(&config.bot_token));
     let update_params_builder = GetUpdatesParams::builder();
     let mut update_params = update_params_builder.clone().build();
-    let api_search = Arc::clone(&api_shared);
-    let api_repl = Arc::clone(&api_shared);
+    let api_search: Arc<dyn Messenger> = api_shared.clone();
+    let api_repl: Arc<dyn Messenger> = api_shared.clone();
+
+    let muus_shared = Arc::new(MuusClient::new(&config.base_url, config.muus_settings()));
+    let muus_search = Arc::clone(&muus_shared);
+    let muus_repl = Arc::clone(&muus_shared);
+
+    let config_shared = Arc::new(config);
+    let config_search = Arc::clone(&config_shared);
+    let config_repl = Arc::clone(&config_shared);
 
     // Muus search
     let handle_search = thread::spawn(move || {
-        task_search(state_search.clone(), api_search.clone())
+        task_search(state_search.clone(), api_search.clone(), muus_search.clone(), config_search.clone())
     });
 
     // REPL handling
-    let handle_repl = thread::spawn(move || {
-        loop {
-            let ret = api_repl.clone().get_updates(&update_params);
-            match ret {
-                Ok(response) => {
-                    for update in response.result {
-                        if let UpdateContent::Message(message) = update.content {
-                            task_repl(state_repl.clone(), api_repl.clone(), message.clone());
+    let handle_repl = match config_repl.mode {
+        UpdateMode::Polling => {
+            // Telegram refuses to give out the updates while a webhook is set
+            if let Err(err) = api_shared.delete_webhook(&DeleteWebhookParams::builder().build()) {
+                println!("Failed to delete webhook: {err:?}");
+            }
+            thread::spawn(move || {
+                loop {
+                    let ret = api_repl.get_updates(&update_params);
+                    match ret {
+                        Ok(updates) => {
+                            for update in updates {
+                                update_params = update_params_builder
+                                    .clone()
+                                    .offset(update.update_id + 1)
+                                    .build();
+                                task_update(state_repl.clone(), api_repl.clone(), muus_repl.clone(),
+                                            config_repl.clone(), update);
+                            }
                         }
-                        update_params = update_params_builder
-                            .clone()
-                            .offset(update.update_id + 1)
-                            .build();
+                        Err(error) => { println!("Failed to get updates: {error}") }
                     }
+                    thread::sleep(Duration::from_secs(config_repl.poll_interval));
+                }
+            })
+        }
+        UpdateMode::Webhook => {
+            let server = match WebhookServer::bind(&config_repl.webhook_addr, &config_repl.webhook_path,
+                                                   &config_repl.webhook_secret) {
+                Ok(server) => server,
+                Err(err) => {
+                    eprintln!("Failed to start the webhook server on {}: {err}", config_repl.webhook_addr);
+                    std::process::exit(1);
+                }
+            };
+            let addr = server.local_addr().map_or(config_repl.webhook_addr.clone(), |addr| addr.to_string());
+            println!("Receiving updates on {addr}{}", config_repl.webhook_path);
+            if config_repl.webhook_url.is_empty() {
+                println!("Webhook URL is not set, register the webhook with Telegram by hand");
+            } else {
+                let swp = SetWebhookParams::builder()
+                    .url(config_repl.webhook_url.clone())
+                    .secret_token(config_repl.webhook_secret.clone())
+                    .build();
+                if let Err(err) = api_shared.set_webhook(&swp) {
+                    eprintln!("Failed to set webhook {}: {err:?}", config_repl.webhook_url);
+                    std::process::exit(1);
                 }
-                Err(error) => { println!("Failed to get updates: {error:?}") }
             }
-            thread::sleep(Duration::from_secs(TG_POLL_INTERVAL));
+            thread::spawn(move || {
+                server.serve(|update| task_update(state_repl.clone(), api_repl.clone(), muus_repl.clone(),
+                                                  config_repl.clone(), update));
+            })
         }
-    });
+    };
 
     _ = handle_search.join();
     _ = handle_repl.join();
 }
 
-fn task_search(state: Arc<Mutex<SharedState>>, api: Arc<Api>) {
+/// Imports the Python bot's db into the configured store: `import <db.yaml> [flags]`.
+fn run_import(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (path, flags) = match args.split_first() {
+        Some((path, flags)) if !path.starts_with("--") => (path, flags),
+        _ => return Err("specify the path of db.yaml, e.g. `import ../python/db.yaml`".into()),
+    };
+    let config = Config::load(flags)?;
+    let yaml = fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+    let mut store = open_store(&config)?;
+    let report = import::import_python_db(store.as_mut(), &yaml)?;
+    println!("{report}");
+    Ok(())
+}
+
+fn task_search(state: Arc<Mutex<SharedState>>, api: Arc<dyn Messenger>, muus: Arc<MuusClient>, config: Arc<Config>) {
     loop {
-        let mut state = state.lock().unwrap();
-        let mut mss_todo: Vec<MuusSearch> = Vec::new();
+        run_searches(&state, api.as_ref(), &muus, &config, Utc::now());
+        thread::sleep(Duration::from_secs(config.monitor_interval));
+    }
+}
 
-        // Build a vector of searches to run
-        let dtime_now = Utc::now();
-        for db_user in state.db.iter() {
-            let db_qs = &db_user.queries;
-            for q in db_qs.iter() {
-                // println!("Chat ID {}, query index {}, text {}", chat_id_str.clone(), q.text, q.index);
-                if (dtime_now - q.dtime_prev_req).num_seconds() >= QUERY_COOLDOWN_INTERVAL as i64 {
-                    let ms = MuusSearch {
-                        user_id: db_user.user_id.clone(),
-                        chat_id: db_user.chat_id.clone(),
-                        uniq_id: q.uniq_id.clone(),
-                        text: q.text.clone(),
-                        dtime_req: dtime_now.clone()
+/// Runs the searches due at `dtime_now` and sends their news.
+fn run_searches(state: &Mutex<SharedState>, api: &dyn Messenger, muus: &MuusClient, config: &Config,
+                dtime_now: DateTime<Utc>) {
+    // A panic in the other task must not stop the search
+    let lock = || state.lock().unwrap_or_else(PoisonError::into_inner);
+    // Only hold the state while reading and writing the db, not during the network requests
+    let mss_todo = due_searches(lock().store.as_ref(), config, dtime_now);
+
+    // Identical searches of several users are fetched once
+    for group in group_searches(mss_todo) {
+        // println!("Requesting {} for {} queries", group.search.text, group.members.len());
+        match muus.search_muus_market(group.search.clone()) {
+            Ok(results) => {
+                if !results.warnings.is_empty() {
+                    println!("Query {} parsed {} of {} rows", group.search.text,
+                             results.listings.len(), results.rows);
+                    for warning in results.warnings.iter() {
+                        println!("  {warning}");
+                    }
+                }
+
+                // Excerpts of the new listings by ID, also fetched once for the whole group
+                let mut excerpts: HashMap<u64, Option<String>> = HashMap::new();
+                for ms_todo in group.members.iter() {
+                    let ret = apply_results(lock().store.as_mut(), ms_todo, results.listings.clone());
+                    let (news, timezone) = match ret {
+                        Ok(Some(ret)) => ret,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            // Sending without recording would repeat the news on the next run
+                            println!("Failed to save query for user {}: {}: {err}", ms_todo.user_id, ms_todo.text);
+                            continue;
+                        }
                     };
-                    mss_todo.push(ms);
+
+                    let notifications = send_news(api, muus, ms_todo, news, timezone, &mut excerpts);
+                    let mut state = lock();
+                    for notification in notifications.iter() {
+                        if let Err(err) = state.store.log_notification(notification) {
+                            println!("Failed to log notification: {err}");
+                        }
+                    }
                 }
+            },
+            Err(err) => println!("Query {} for {} queries failed: {err}", group.search.text, group.members.len()),
+        };
+    }
+}
+
+/// Snapshot of the queries to run at `dtime_now`.
+fn due_searches(store: &dyn Store, config: &Config, dtime_now: DateTime<Utc>) -> Vec<MuusSearch> {
+    let mut mss_todo: Vec<MuusSearch> = Vec::new();
+    let db_users = store.users().unwrap_or_else(|err| {
+        println!("Failed to read the db: {err}");
+        Vec::new()
+    });
+    for db_user in db_users.iter() {
+        for q in db_user.queries.iter() {
+            if q.paused {
+                continue;
+            }
+            let is_due = match q.dtime_prev_req {
+                Some(dtime_prev) => (dtime_now - dtime_prev).num_seconds() >= q.interval(config) as i64,
+                None => true,
+            };
+            if is_due {
+                let ms = MuusSearch {
+                    user_id: db_user.user_id.clone(),
+                    chat_id: db_user.chat_id,
+                    uniq_id: q.uniq_id,
+                    text: q.text.clone(),
+                    dtime_req: dtime_now,
+                    dtime_prev_req: q.dtime_prev_req,
+                    seen_ids: q.seen.iter().map(|s| s.id).collect(),
+                    filters: q.filters.clone(),
+                    imported: q.imported,
+                };
+                mss_todo.push(ms);
             }
         }
+    }
+    mss_todo
+}
 
-        for ms_todo in mss_todo.iter() {
-            // println!("Requesting {} {}", ms_todo.user_id, ms_todo.text.clone());
-            let ret = search_muus_market(ms_todo.clone());
-            let pos_user = state.db.iter().position(|u| u.chat_id == ms_todo.chat_id).unwrap();
-            match ret {
-                Ok(listings) => {
-                    // Update the query time in the db
-                    let qs = &state.db.get(pos_user).unwrap().queries;
-                    let q_pos = qs.iter().position(|q| q.uniq_id.eq(&ms_todo.uniq_id)).unwrap();
-                    let q_upd = DBQuery {
-                        uniq_id: ms_todo.uniq_id.clone(),
-                        text: ms_todo.text.clone(),
-                        dtime_prev_req: ms_todo.dtime_req,
-                    };
-                    _ = state.db.get_mut(pos_user).unwrap().queries.remove(q_pos);
-                    _ = state.db.get_mut(pos_user).unwrap().queries.push(q_upd);
-                    _ = fs::write(PATH_DB, serde_json::to_string_pretty(&state.db).unwrap());
-
-                    for listing in listings {
-                        // Compose the message
-                        let mut response = format!("<i>From query '{}':</i>", ms_todo.text.clone());
-                        response.push_str(format!("\n<b>{}</b>", listing.title).as_str());
-                        response.push_str(format!("\n{}", listing.url).as_str());
-                        response.push_str(format!("\n{}", listing.dtime_upd).as_str());
-
-                        // Send news listings to the chat
-                        let smp = SendMessageParams::builder()
-                            .chat_id(ChatId::Integer(ms_todo.chat_id))
-                            .text(response)
-                            .parse_mode(Html)
-                            .build();
-
-                        if let Err(err) = api.send_message(&smp) {
-                            println!("Failed to send message: {err:?}");
-                        }
+/// Records the results of the search in its query and returns the news to send.
+///
+/// `None` if the query was removed, paused, or changed while the search ran, as the results
+/// are then stale. A changed query stays due and runs again with its new settings.
+fn apply_results(store: &mut dyn Store, ms: &MuusSearch, listings: Vec<MuusListing>)
+                 -> Result<Option<(Vec<ListingNews>, Tz)>, StoreError> {
+    let Some(db_user) = store.user(ms.chat_id)? else { return Ok(None) };
+    let Some(mut q) = db_user.queries.into_iter().find(|q| q.uniq_id == ms.uniq_id) else { return Ok(None) };
+    if q.text != ms.text || q.filters != ms.filters || q.dtime_prev_req != ms.dtime_prev_req || q.paused {
+        println!("Query for user {} changed during the search, dropping the results: {}", ms.user_id, ms.text);
+        return Ok(None);
+    }
+
+    // Update the query time and the seen listings in the db
+    let mut news = q.take_news(listings, ms.dtime_req);
+    if !store.save_run(ms.chat_id, q.uniq_id, ms.dtime_req, &q.seen)? {
+        return Ok(None);
+    }
+    if !q.notify_edits {
+        news.retain(|n| matches!(n, ListingNews::New(_)));
+    }
+    // Queries imported from the Python bot or an older db have no seen listings yet, so use the old rule once
+    if let (true, Some(dtime_prev)) = (ms.imported, ms.dtime_prev_req) {
+        news.retain(|n| match n {
+            ListingNews::New(listing) | ListingNews::Edited { listing, .. } => listing.dtime_upd >= dtime_prev,
+        });
+    }
+    let title_re = q.local_filter.title_re();
+    news.retain(|n| match n {
+        ListingNews::New(listing) | ListingNews::Edited { listing, .. } => {
+            q.local_filter.matches(listing, title_re.as_ref())
+        }
+    });
+
+    // The first run of a query just sets the baseline
+    if ms.dtime_prev_req.is_none() {
+        news.clear();
+    }
+    Ok(Some((news, db_user.timezone)))
+}
+
+/// Sends the news of the search to its chat, returns the ones sent.
+///
+/// The description excerpts of new listings are looked up in `excerpts` first, and the fetched ones added to it.
+fn send_news(api: &dyn Messenger, muus: &MuusClient, ms: &MuusSearch, news: Vec<ListingNews>,
+             timezone: Tz, excerpts: &mut HashMap<u64, Option<String>>) -> Vec<DBNotification> {
+    let mut notifications: Vec<DBNotification> = Vec::new();
+    for n in news {
+        // Brand-new listings get a description excerpt from their page
+        let excerpt = match &n {
+            ListingNews::New(listing) => excerpts.entry(listing.id).or_insert_with(|| {
+                match muus.fetch_listing_detail(&listing.url) {
+                    Ok(detail) => Some(compose_excerpt(&detail.description)),
+                    Err(err) => {
+                        println!("Failed to fetch listing {}: {err}", listing.url);
+                        None
                     }
-                },
-                Err(_) => println!("Query for user {} failed: {}", ms_todo.user_id, ms_todo.text),
+                }
+            }).clone(),
+            ListingNews::Edited { .. } => None,
+        };
+        let response = compose_news(&ms.text, &n, excerpt.as_deref(), timezone);
+
+        // Send news listings to the chat
+        let listing = match &n {
+            ListingNews::New(listing) | ListingNews::Edited { listing, .. } => listing,
+        };
+        let callback_data = format!("{}{}", CALLBACK_DETAIL, listing.url.trim_start_matches(muus.base_url()));
+        let smp = SendMessageParams::builder()
+            .chat_id(ChatId::Integer(ms.chat_id))
+            .text(response)
+            .parse_mode(Html);
+        // Telegram limits the button data to 64 bytes
+        let smp = if callback_data.len() <= 64 {
+            let button = InlineKeyboardButton::builder()
+                .text("Details")
+                .callback_data(callback_data)
+                .build();
+            let markup = InlineKeyboardMarkup::builder()
+                .inline_keyboard(vec![vec![button]])
+                .build();
+            smp.reply_markup(ReplyMarkup::InlineKeyboardMarkup(markup)).build()
+        } else {
+            smp.build()
+        };
+
+        match api.send_message(&smp) {
+            Ok(_) => notifications.push(DBNotification {
+                chat_id: ms.chat_id,
+                uniq_id: ms.uniq_id,
+                listing_id: listing.id,
+                title: listing.title.clone(),
+                url: listing.url.clone(),
+                edited: matches!(n, ListingNews::Edited { .. }),
+                dtime_sent: Utc::now(),
+            }),
+            Err(err) => println!("Failed to send message: {err}"),
+        }
+    }
+    notifications
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// One-line summary of the listing, e.g. "Selling | 450 € | Helsinki | seller".
+fn compose_summary(listing: &MuusListing) -> String {
+    let mut details: Vec<String> = Vec::new();
+    if let Some(kind) = listing.kind { details.push(kind.to_string()); }
+    if let Some(price) = listing.price { details.push(format!("{} €", price)); }
+    if let Some(region) = &listing.region { details.push(escape_html(region)); }
+    if let Some(seller) = &listing.seller { details.push(escape_html(seller)); }
+    details.join(" | ")
+}
+
+/// First `EXCERPT_LENGTH` chars of the description, on a single line.
+fn compose_excerpt(description: &str) -> String {
+    let text = description.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if text.chars().count() > EXCERPT_LENGTH {
+        format!("{}\u{2026}", text.chars().take(EXCERPT_LENGTH).collect::<String>())
+    } else {
+        text
+    }
+}
+
+/// Formats a period in seconds, e.g. "4h", "1d 12h", "15m".
+fn format_interval(secs: u64) -> String {
+    let parts: Vec<String> = [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")].iter()
+        .scan(secs, |rest, (unit, suffix)| {
+            let n = *rest / unit;
+            *rest %= unit;
+            Some((n, suffix))
+        })
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, suffix)| format!("{}{}", n, suffix))
+        .collect();
+    if parts.is_empty() { "0s".to_string() } else { parts.join(" ") }
+}
+
+/// Parses a period of "/interval", e.g. "15m", "4h", "1d" (bare numbers are seconds).
+fn parse_interval(text: &str) -> Result<u64, String> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let multiplier = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("Incorrect interval: <b>{}</b>.", escape_html(text))),
+    };
+    let secs = value.parse::<u64>().ok().and_then(|v| v.checked_mul(multiplier))
+        .ok_or_else(|| format!("Incorrect interval: <b>{}</b>.", escape_html(text)))?;
+    Ok(secs)
+}
+
+/// Formats the time in the zone of the user, e.g. "14.03.2024 18:22 EET".
+fn format_dtime(dtime: DateTime<Utc>, timezone: Tz) -> String {
+    dtime.with_timezone(&timezone).format("%d.%m.%Y %H:%M %Z").to_string()
+}
+
+fn compose_detail(detail: &MuusListingDetail, timezone: Tz) -> String {
+    // Leave room for the other fields within the 4096 chars limit of Telegram
+    let description: String = detail.description.chars().take(3000).collect();
+    let mut response = format!("<b>{}</b>", escape_html(&detail.title));
+    if !description.is_empty() {
+        response.push_str(format!("\n\n{}\n", escape_html(&description)).as_str());
+    }
+    if let Some(seller) = &detail.seller {
+        response.push_str(format!("\nSeller: {}", escape_html(seller)).as_str());
+    }
+    if let Some(contact) = &detail.contact {
+        response.push_str(format!("\nContact: {}", escape_html(contact)).as_str());
+    }
+    if let Some(dtime_add) = detail.dtime_add {
+        response.push_str(format!("\nAdded: {}", format_dtime(dtime_add, timezone)).as_str());
+    }
+    if let Some(dtime_upd) = detail.dtime_upd {
+        response.push_str(format!("\nModified: {}", format_dtime(dtime_upd, timezone)).as_str());
+    }
+    for (i, image_url) in detail.image_urls.iter().enumerate() {
+        response.push_str(format!("\n<a href=\"{}\">Image {}</a>", escape_html(image_url), i + 1).as_str());
+    }
+    response.push_str(format!("\n{}", detail.url).as_str());
+    response
+}
+
+fn compose_news(query: &str, news: &ListingNews, excerpt: Option<&str>, timezone: Tz) -> String {
+    let mut response = String::new();
+    match news {
+        ListingNews::New(listing) => {
+            response.push_str(format!("<i>From query '{}':</i>", escape_html(query)).as_str());
+            response.push_str(format!("\n<b>{}</b>", escape_html(&listing.title)).as_str());
+            let summary = compose_summary(listing);
+            if !summary.is_empty() {
+                response.push_str(format!("\n{}", summary).as_str());
+            }
+            if let Some(excerpt) = excerpt.filter(|e| !e.is_empty()) {
+                response.push_str(format!("\n<i>{}</i>", escape_html(excerpt)).as_str());
+            }
+            response.push_str(format!("\n{}", listing.url).as_str());
+            response.push_str(format!("\n{}", format_dtime(listing.dtime_upd, timezone)).as_str());
+        },
+        ListingNews::Edited { listing, title_prev, price_prev } => {
+            let fmt_price = |p: &Option<u32>| match p {
+                Some(p) => format!("{} €", p),
+                None => "-".to_string(),
             };
-            // Wait random small interval
-            thread::sleep(Duration::from_secs(1));
+            response.push_str(format!("\u{270F}\u{FE0F} <i>Updated in query '{}':</i>", escape_html(query)).as_str());
+            response.push_str(format!("\n<b>{}</b>", escape_html(&listing.title)).as_str());
+            let mut is_described = false;
+            if !title_prev.is_empty() && *title_prev != listing.title {
+                response.push_str(format!("\nTitle: {} \u{2192} {}",
+                                          escape_html(title_prev), escape_html(&listing.title)).as_str());
+                is_described = true;
+            }
+            if *price_prev != listing.price {
+                response.push_str(format!("\nPrice: {} \u{2192} {}",
+                                          fmt_price(price_prev), fmt_price(&listing.price)).as_str());
+                is_described = true;
+            }
+            if !is_described {
+                response.push_str("\nDescription changed");
+            }
+            response.push_str(format!("\n{}", listing.url).as_str());
+            response.push_str(format!("\n{}", format_dtime(listing.dtime_upd, timezone)).as_str());
+        },
+    }
+    response
+}
+
+/// Parses the search options of "/add", e.g. "--type=myydaan --max=300 --region=uusimaa".
+fn parse_filters(args: &[&str]) -> Result<MuusFilters, String> {
+    let mut filters = MuusFilters::default();
+    for arg in args {
+        let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+        let parse_price = |v: &str| v.parse::<u32>().map_err(|_| format!("Incorrect price: <b>{}</b>.", escape_html(v)));
+        match key {
+            "--type" => {
+                filters.kind = Some(MuusListingKind::from_param(&value.to_lowercase())
+                    .ok_or(format!("Incorrect type, use <b>myydaan</b> or <b>ostetaan</b>: <b>{}</b>.",
+                                   escape_html(value)))?);
+            }
+            "--min" => filters.price_min = Some(parse_price(value)?),
+            "--max" => filters.price_max = Some(parse_price(value)?),
+            "--region" if !value.is_empty() => filters.region = Some(value.to_lowercase()),
+            "--category" if !value.is_empty() => filters.category = Some(value.to_lowercase()),
+            "--titles" => filters.titles_only = true,
+            _ => return Err(format!("Unknown option: <b>{}</b>.", escape_html(arg))),
+        }
+    }
+    Ok(filters)
+}
+
+/// Parses the rules of "/filter", e.g. "--require=digitakt --exclude=rikki --regex=mk2 --max=300".
+fn parse_local_filter(args: &[&str]) -> Result<MuusLocalFilter, String> {
+    let mut filter = MuusLocalFilter::default();
+    for arg in args {
+        let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+        let parse_price = |v: &str| v.parse::<u32>().map_err(|_| format!("Incorrect price: <b>{}</b>.", escape_html(v)));
+        match key {
+            "--require" if !value.is_empty() => filter.require.push(value.to_string()),
+            "--exclude" if !value.is_empty() => filter.exclude.push(value.to_string()),
+            "--regex" => {
+                MuusLocalFilter::compile_regex(value)
+                    .map_err(|_| format!("Incorrect regex: <b>{}</b>.", escape_html(value)))?;
+                filter.title_regex = Some(value.to_string());
+            }
+            "--min" => filter.price_min = Some(parse_price(value)?),
+            "--max" => filter.price_max = Some(parse_price(value)?),
+            "--clear" => {}
+            _ => return Err(format!("Unknown option: <b>{}</b>.", escape_html(arg))),
         }
-        drop(state);
-        thread::sleep(Duration::from_secs(QUERY_MONITOR_INTERVAL));
     }
+    Ok(filter)
 }
 
 fn parse_command(text: &str) -> TgCommand {
-    let mut parts = text.trim().split_whitespace();
+    let mut parts = text.split_whitespace();
     let command = parts.next().unwrap_or("");
     let args: Vec<&str> = parts.collect();
 
     match command {
         "/help" => TgCommand::Help,
         "/start" => TgCommand::Start,
-        "/add" => TgCommand::Add(args.join("+")),
+        "/add" => {
+            let (options, keywords): (Vec<&str>, Vec<&str>) = args.iter().partition(|e| e.starts_with("--"));
+            match parse_filters(&options) {
+                Ok(filters) => TgCommand::Add(keywords.join("+"), filters),
+                Err(err) => TgCommand::Invalid(err),
+            }
+        },
         "/list" => TgCommand::List,
-        "/remove" => TgCommand::Remove(args.iter().map(|e| e.parse::<u64>().unwrap()).collect()),
+        "/remove" => match args.iter().find(|e| e.parse::<u64>().is_err()) {
+            Some(e) => TgCommand::Invalid(format!("Incorrect index: <b>{}</b>.", escape_html(e))),
+            None => TgCommand::Remove(args.iter().filter_map(|e| e.parse::<u64>().ok()).collect()),
+        },
+        "/edits" => match (args.first().map(|e| (e, e.parse::<u64>())), args.get(1)) {
+            (Some((_, Ok(idx))), Some(&"on")) => TgCommand::Edits(idx, true),
+            (Some((_, Ok(idx))), Some(&"off")) => TgCommand::Edits(idx, false),
+            (Some((_, Ok(_))), _) => TgCommand::Invalid("Specify <b>on</b> or <b>off</b>.".to_string()),
+            (Some((e, Err(_))), _) => TgCommand::Invalid(format!("Incorrect index: <b>{}</b>.", escape_html(e))),
+            (None, _) => TgCommand::Invalid("Specify the query index.".to_string()),
+        },
+        "/pause" | "/resume" => match args.first().map(|e| (e, e.parse::<u64>())) {
+            Some((_, Ok(idx))) => TgCommand::Pause(idx, command == "/pause"),
+            Some((e, Err(_))) => TgCommand::Invalid(format!("Incorrect index: <b>{}</b>.", escape_html(e))),
+            None => TgCommand::Invalid("Specify the query index.".to_string()),
+        },
+        "/interval" => match (args.first().map(|e| (e, e.parse::<u64>())), args.get(1)) {
+            (Some((_, Ok(idx))), Some(&"default")) => TgCommand::Interval(idx, None),
+            (Some((_, Ok(idx))), Some(value)) => match parse_interval(value) {
+                Ok(secs) => TgCommand::Interval(idx, Some(secs)),
+                Err(err) => TgCommand::Invalid(err),
+            },
+            (Some((_, Ok(_))), None) => TgCommand::Invalid("Specify the interval, e.g. <b>4h</b>.".to_string()),
+            (Some((e, Err(_))), _) => TgCommand::Invalid(format!("Incorrect index: <b>{}</b>.", escape_html(e))),
+            (None, _) => TgCommand::Invalid("Specify the query index.".to_string()),
+        },
+        "/filter" => match args.split_first() {
+            Some((idx, options)) => match (idx.parse::<u64>(), options.is_empty()) {
+                (Ok(idx), true) => TgCommand::Filter(idx, None),
+                (Ok(idx), false) => match parse_local_filter(options) {
+                    Ok(filter) => TgCommand::Filter(idx, Some(filter)),
+                    Err(err) => TgCommand::Invalid(err),
+                },
+                (Err(_), _) => TgCommand::Invalid(format!("Incorrect index: <b>{}</b>.", escape_html(idx))),
+            },
+            None => TgCommand::Invalid("Specify the query index.".to_string()),
+        },
+        "/show" => TgCommand::Show(args.first().unwrap_or(&"").to_string()),
+        "/timezone" => TgCommand::Timezone(args.first().map(|e| e.to_string())),
+        "/history" => TgCommand::History,
         "/clear" => TgCommand::Clear,
         "/stop" => TgCommand::Stop,
         _ => TgCommand::Unknown(()),
     }
 }
 
-fn task_repl(state: Arc<Mutex<SharedState>>, api: Arc<Api>, message: Message) {
-    let mut state = state.lock().unwrap();
-    let user_id = message.chat.username.unwrap();
+/// Handles an update received by polling or on the webhook.
+fn task_update(state: Arc<Mutex<SharedState>>, api: Arc<dyn Messenger>, muus: Arc<MuusClient>, config: Arc<Config>,
+               update: Update) {
+    match update.content {
+        UpdateContent::Message(message) => task_repl(state, api, muus, config, message),
+        UpdateContent::CallbackQuery(callback_query) => task_callback(state, api, muus, callback_query),
+        _ => {}
+    }
+}
+
+fn task_repl(state: Arc<Mutex<SharedState>>, api: Arc<dyn Messenger>, muus: Arc<MuusClient>, config: Arc<Config>,
+             message: Message) {
+    // Photos, stickers and the like have no text, and accounts are keyed by the username
+    let (Some(user_id), Some(text)) = (message.chat.username, message.text) else {
+        return;
+    };
+    let lock = || state.lock().unwrap_or_else(PoisonError::into_inner);
     let chat_id = message.chat.id;
-    let chat_id_known = state.db.iter().map(|u| u.chat_id == chat_id).count() != 0usize;
 
     let reply_parameters = ReplyParameters::builder()
         .message_id(message.message_id)
         .build();
 
+    let command = parse_command(&text);
+    let result = match command {
+        // Only hold the state for the time zone, not during the network request
+        TgCommand::Show(url) => {
+            let timezone = lock().store.user(chat_id).map(|u| u.map_or(DEFAULT_TIMEZONE, |u| u.timezone));
+            timezone.map(|timezone| show_listing(&muus, &url, timezone))
+        }
+        command => run_command(lock().store.as_mut(), &config, chat_id, &user_id, command),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(err) => {
+            println!("Command for user {} failed: {err}", user_id);
+            "\u{02757} Failed to access the database, try again later.".to_string()
+        }
+    };
+
+    if !response.is_empty() {
+        let smp = SendMessageParams::builder()
+            .chat_id(ChatId::Integer(chat_id))
+            .text(response)
+            .parse_mode(Html)
+            .reply_parameters(reply_parameters)
+            .build();
+        if let Err(err) = api.send_message(&smp) {
+            println!("Failed to send message: {err}");
+        }
+    }
+}
+
+/// Fetches the listing page and returns its details, or the reason it cannot be shown.
+fn show_listing(muus: &MuusClient, url: &str, timezone: Tz) -> String {
+    // Only the marketplace pages can be fetched
+    if url.starts_with(format!("{}/tori/", muus.base_url()).as_str()) {
+        match muus.fetch_listing_detail(url) {
+            Ok(detail) => compose_detail(&detail, timezone),
+            Err(_) => format!("\u{02757} Failed to fetch the listing: {}.", escape_html(url)),
+        }
+    } else {
+        format!("\u{02757} Not a listing link: <b>{}</b>.", escape_html(url))
+    }
+}
+
+/// Executes the command and returns the response to send.
+///
+/// Not for "/show", which `task_repl` runs without holding the state, as it fetches the listing.
+fn run_command(store: &mut dyn Store, config: &Config, chat_id: i64, user_id: &str,
+               command: TgCommand) -> Result<String, StoreError> {
+    let db_user = store.user(chat_id)?;
+    let timezone = db_user.as_ref().map_or(DEFAULT_TIMEZONE, |u| u.timezone);
+
     let mut response = String::new();
-    match parse_command(&message.text.unwrap()) {
+    match command {
         TgCommand::Help => { response = "See the supported commands in the menu.".to_string() },
         TgCommand::Start => {
-            if chat_id_known {
+            if db_user.is_some() {
                 response = format!("\u{1F64B} You already have an account under ID <b>{}</b>.", user_id);
             } else {
-                let qs: Vec<DBQuery> = Vec::new();
                 let entry = DBUser {
-                    chat_id: chat_id.clone(),
-                    user_id: user_id.clone(),
-                    queries: qs,
+                    chat_id,
+                    user_id: user_id.to_string(),
+                    queries: Vec::new(),
+                    timezone: DEFAULT_TIMEZONE,
                 };
-                state.db.push(entry);
-                _ = fs::write(PATH_DB, serde_json::to_string_pretty(&state.db).unwrap());
+                store.put_user(&entry)?;
                 response = format!("\u{1F64B} Welcome to the service! Your account ID is <b>{}</b> .", user_id);
             }
         },
-        TgCommand::Add(q_new) => {
-            if chat_id_known {
+        TgCommand::Add(q_new, filters) => {
+            if let Some(db_user) = db_user {
                 if q_new.is_empty() {
                     response = "\u{02757} Cannot create an empty query.".to_string();
                 } else {
-                    let pos_user = state.db.iter().position(|u| u.chat_id == chat_id).unwrap();
-                    let qs: &Vec<DBQuery> = &state.db.get(pos_user).unwrap().queries;
-                    if qs.iter().any(|q| q.text.eq(&q_new)) {
-                        response = format!("\u{02757} Query already exists: <b>{}</b>.", q_new);
+                    let qs: &Vec<DBQuery> = &db_user.queries;
+                    if qs.iter().any(|q| q.text.eq(&q_new) && q.filters == filters) {
+                        response = format!("\u{02757} Query already exists: <b>{}</b>.", escape_html(&q_new));
                     } else {
                         // Use the first available index
-                        let qs_ids: HashSet<u64> = qs.iter().map(|x| x.uniq_id.clone()).collect();
+                        let qs_ids: HashSet<u64> = qs.iter().map(|x| x.uniq_id).collect();
                         let seq_idcs = HashSet::from_iter(0..(qs.len() as u64) + 1);
                         let idx = *seq_idcs.difference(&qs_ids).next().unwrap();
-                        let dbq = DBQuery {
-                            uniq_id: idx,
-                            text: q_new.clone(),
-                            dtime_prev_req: Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()
-                        };
-                        _ = state.db.get_mut(pos_user).unwrap().queries.push(dbq);
-                        _ = fs::write(PATH_DB, serde_json::to_string_pretty(&state.db).unwrap());
-                        response = format!("\u{02705} Query added: <b>{}</b>.", q_new);
+                        let dbq = DBQuery { filters: filters.clone(), ..DBQuery::new(idx, &q_new) };
+                        store.put_query(chat_id, &dbq)?;
+                        response = format!("\u{02705} Query added: <b>{}</b>.", escape_html(&q_new));
+                        if filters != MuusFilters::default() {
+                            response.push_str(format!(" Filters: {}.", escape_html(&filters.to_string())).as_str());
+                        }
                     }
                 }
             } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
         },
         TgCommand::List => {
-            if chat_id_known {
-                let pos_user = state.db.iter().position(|u| u.chat_id == chat_id).unwrap();
-                let qs: &Vec<DBQuery> = &state.db.get(pos_user).unwrap().queries;
-                if qs.len() > 0 {
+            if let Some(db_user) = db_user {
+                let qs: &Vec<DBQuery> = &db_user.queries;
+                if !qs.is_empty() {
                     response = "\u{1F4CB} Existing queries:".to_string();
                     for q in qs.iter() {
-                        response.push_str(format!("\n[{}]: <b>{}</b> (upd: {})",
-                                                  q.uniq_id, q.text, q.dtime_prev_req).as_str());
+                        let upd = match q.dtime_prev_req {
+                            Some(dtime) => format_dtime(dtime, timezone),
+                            None => "never".to_string(),
+                        };
+                        response.push_str(format!("\n[{}]: <b>{}</b>", q.uniq_id, escape_html(&q.text)).as_str());
+                        if q.filters != MuusFilters::default() {
+                            response.push_str(format!(" ({})", escape_html(&q.filters.to_string())).as_str());
+                        }
+                        if q.local_filter != MuusLocalFilter::default() {
+                            response.push_str(format!(" [{}]", escape_html(&q.local_filter.to_string())).as_str());
+                        }
+                        response.push_str(format!(" (upd: {})", upd).as_str());
+                        if q.interval.is_some() {
+                            response.push_str(format!(" [every {}]", format_interval(q.interval(config))).as_str());
+                        }
+                        if !q.notify_edits {
+                            response.push_str(" [edits off]");
+                        }
+                        if q.paused {
+                            response.push_str(" [paused]");
+                        }
                     }
                 } else { response = "\u{1F4CB} No queries found.".to_string(); }
             } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
         },
         TgCommand::Remove(idcs) => {
-            if chat_id_known {
-                let pos_user = state.db.iter().position(|u| u.chat_id == chat_id).unwrap();
+            if let Some(db_user) = db_user {
                 for idx in idcs.iter() {
-                    let qs_ids: Vec<u64> = state.db.get(pos_user).unwrap().queries
-                        .iter().map(|q| q.uniq_id).collect();
-                    let qs_texts: Vec<String> = state.db.get(pos_user).unwrap().queries
-                        .iter().map(|q| q.text.clone()).collect();
-
-                    match qs_ids.iter().position(|i| i.eq(idx)) {
-                        Some(pos_q) => {
-                            state.db.get_mut(pos_user).unwrap().queries.remove(pos_q);
+                    match db_user.queries.iter().find(|q| q.uniq_id == *idx) {
+                        Some(q) if store.remove_query(chat_id, *idx)? => {
                             response.push_str(format!("\u{0274E} Query removed: <b>{}</b> (<b>{}</b>).\n",
-                                                      idx, qs_texts[pos_q]).as_str());
+                                                      idx, escape_html(&q.text)).as_str());
+                        }
+                        _ => { response.push_str(format!("\u{02757} Incorrect index: <b>{}</b>.\n", idx).as_str()); }
+                    }
+                }
+            } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
+        },
+        TgCommand::Edits(idx, is_on) => {
+            if let Some(db_user) = db_user {
+                match db_user.queries.into_iter().find(|q| q.uniq_id == idx) {
+                    Some(mut q) => {
+                        q.notify_edits = is_on;
+                        store.put_query(chat_id, &q)?;
+                        response = format!("\u{270F}\u{FE0F} Edit notifications {} for query: <b>{}</b> (<b>{}</b>).",
+                                           if is_on { "enabled" } else { "disabled" }, idx, escape_html(&q.text));
+                    }
+                    None => { response = format!("\u{02757} Incorrect index: <b>{}</b>.", idx); }
+                }
+            } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
+        },
+        TgCommand::Pause(idx, is_paused) => {
+            if let Some(db_user) = db_user {
+                match db_user.queries.into_iter().find(|q| q.uniq_id == idx) {
+                    Some(mut q) => {
+                        q.paused = is_paused;
+                        store.put_query(chat_id, &q)?;
+                        response = if is_paused {
+                            format!("\u{023F8}\u{FE0F} Query paused: <b>{}</b> (<b>{}</b>).",
+                                    idx, escape_html(&q.text))
+                        } else {
+                            format!("\u{025B6}\u{FE0F} Query resumed: <b>{}</b> (<b>{}</b>).",
+                                    idx, escape_html(&q.text))
+                        };
+                    }
+                    None => { response = format!("\u{02757} Incorrect index: <b>{}</b>.", idx); }
+                }
+            } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
+        },
+        TgCommand::Interval(_, Some(secs)) if !(config.interval_min..=config.interval_max).contains(&secs) => {
+            response = format!("\u{02757} The interval must be between <b>{}</b> and <b>{}</b>.",
+                               format_interval(config.interval_min), format_interval(config.interval_max));
+        },
+        TgCommand::Interval(idx, interval) => {
+            if let Some(db_user) = db_user {
+                match db_user.queries.into_iter().find(|q| q.uniq_id == idx) {
+                    Some(mut q) => {
+                        q.interval = interval;
+                        store.put_query(chat_id, &q)?;
+                        response = format!("\u{023F1}\u{FE0F} Query <b>{}</b> (<b>{}</b>) runs every <b>{}</b>.",
+                                           idx, escape_html(&q.text), format_interval(q.interval(config)));
+                    }
+                    None => { response = format!("\u{02757} Incorrect index: <b>{}</b>.", idx); }
+                }
+            } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
+        },
+        TgCommand::Filter(idx, filter) => {
+            if let Some(db_user) = db_user {
+                match db_user.queries.into_iter().find(|q| q.uniq_id == idx) {
+                    Some(mut q) => {
+                        if let Some(filter) = filter {
+                            q.local_filter = filter;
+                            store.put_query(chat_id, &q)?;
                         }
-                        None => { response.push_str(format!("\u{02757} Incorrect index: <b>{}</b>.\n", idx).as_str()); }
+                        response = if q.local_filter == MuusLocalFilter::default() {
+                            format!("\u{1F50D} No filter for query: <b>{}</b> (<b>{}</b>).",
+                                    idx, escape_html(&q.text))
+                        } else {
+                            format!("\u{1F50D} Filter for query <b>{}</b> (<b>{}</b>): {}.",
+                                    idx, escape_html(&q.text), escape_html(&q.local_filter.to_string()))
+                        };
+                    }
+                    None => { response = format!("\u{02757} Incorrect index: <b>{}</b>.", idx); }
+                }
+            } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
+        },
+        TgCommand::Show(_) => unreachable!("the listing is fetched without the state lock"),
+        TgCommand::Timezone(name) => {
+            if let Some(db_user) = db_user {
+                match name.map(|n| n.parse::<Tz>()) {
+                    Some(Ok(tz)) => {
+                        store.put_user(&DBUser { timezone: tz, ..db_user })?;
+                        response = format!("\u{1F552} Time zone set: <b>{}</b>, now {}.",
+                                           tz, format_dtime(Utc::now(), tz));
+                    }
+                    Some(Err(_)) => {
+                        response = "\u{02757} Unknown time zone, use a name like <b>Europe/Helsinki</b>.".to_string();
+                    }
+                    None => {
+                        response = format!("\u{1F552} Your time zone is <b>{}</b>, now {}.",
+                                           timezone, format_dtime(Utc::now(), timezone));
                     }
                 }
-                _ = fs::write(PATH_DB, serde_json::to_string_pretty(&state.db).unwrap());
+            } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
+        },
+        TgCommand::History => {
+            if db_user.is_some() {
+                let notifications = store.notifications(chat_id, HISTORY_LENGTH)?;
+                if !notifications.is_empty() {
+                    response = "\u{1F4DC} Latest notifications:".to_string();
+                    for n in notifications.iter() {
+                        response.push_str(format!("\n[{}]: <a href=\"{}\">{}</a> ({}{})", n.uniq_id, n.url,
+                                                  escape_html(&n.title), format_dtime(n.dtime_sent, timezone),
+                                                  if n.edited { ", updated" } else { "" }).as_str());
+                    }
+                } else { response = "\u{1F4DC} No notifications sent yet.".to_string(); }
             } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
         },
         TgCommand::Clear => {
-            if chat_id_known {
-                let pos_user = state.db.iter().position(|u| u.chat_id == chat_id).unwrap();
-                let v: Vec<DBQuery> = Vec::new();
-                _ = state.db.get_mut(pos_user).unwrap().queries = v;
-                _ = fs::write(PATH_DB, serde_json::to_string_pretty(&state.db).unwrap());
+            if let Some(db_user) = db_user {
+                for q in db_user.queries.iter() {
+                    store.remove_query(chat_id, q.uniq_id)?;
+                }
                 response = "\u{1F6BD} All queries cleared.".to_string();
             } else { response = "\u{1F4CB} Need to create an account first.".to_string(); }
         },
         TgCommand::Stop => {
-            if chat_id_known {
-                let pos = state.db.iter().position(|u| u.chat_id == chat_id).unwrap();
-                state.db.remove(pos);
-                _ = fs::write(PATH_DB, serde_json::to_string_pretty(&state.db).unwrap());
+            if store.remove_user(chat_id)? {
                 response = "\u{1F64B} Your account has been removed.".to_string();
             } else { response = "\u{1F4CB} There is no account associated with you.".to_string(); }
         },
+        TgCommand::Invalid(err) => { response = format!("\u{02757} {}", err); },
         TgCommand::Unknown(_) => { response = "Unknown command.".to_string(); },
         // _ => { response = "Other command.".to_string(); },
     }
+    Ok(response)
+}
 
-    if !response.is_empty() {
-        let smp = SendMessageParams::builder()
-            .chat_id(ChatId::Integer(chat_id))
-            .text(response)
-            .parse_mode(Html)
-            .reply_parameters(reply_parameters)
-            .build();
-        if let Err(err) = api.send_message(&smp) {
-            println!("Failed to send message: {err:?}");
+fn task_callback(state: Arc<Mutex<SharedState>>, api: Arc<dyn Messenger>, muus: Arc<MuusClient>,
+                 callback_query: CallbackQuery) {
+    let chat_id = match &callback_query.message {
+        Some(MaybeInaccessibleMessage::Message(message)) => message.chat.id,
+        Some(MaybeInaccessibleMessage::InaccessibleMessage(message)) => message.chat.id,
+        None => callback_query.from.id as i64,
+    };
+    let timezone = state.lock().unwrap_or_else(PoisonError::into_inner).store.user(chat_id)
+        .ok().flatten()
+        .map_or(DEFAULT_TIMEZONE, |u| u.timezone);
+
+    let mut answer = String::new();
+    // Clients can send any data, so only the marketplace pages can be fetched
+    let path = callback_query.data.as_deref()
+        .and_then(|d| d.strip_prefix(CALLBACK_DETAIL))
+        .filter(|path| path.starts_with("/tori/"));
+    if let Some(path) = path {
+        let url = format!("{}{path}", muus.base_url());
+        match muus.fetch_listing_detail(&url) {
+            Ok(detail) => {
+                let smp = SendMessageParams::builder()
+                    .chat_id(ChatId::Integer(chat_id))
+                    .text(compose_detail(&detail, timezone))
+                    .parse_mode(Html)
+                    .build();
+                if let Err(err) = api.send_message(&smp) {
+                    println!("Failed to send message: {err}");
+                }
+            }
+            Err(_) => { answer = "Failed to fetch the listing.".to_string(); }
+        }
+    }
+
+    // Stops the loading indicator on the button
+    let acqp = AnswerCallbackQueryParams::builder()
+        .callback_query_id(callback_query.id)
+        .text(answer)
+        .build();
+    if let Err(err) = api.answer_callback(&acqp) {
+        println!("Failed to answer callback query: {err}");
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use chrono::TimeDelta;
+    use crate::messenger::MemoryMessenger;
+    use crate::store::JsonStore;
+    use crate::testutil::{dtime, fixture, listing, serve_with, temp_dir};
+
+    fn store(name: &str) -> JsonStore {
+        JsonStore::open(&temp_dir(&format!("main_{name}"), true).join("db.json"), 0).unwrap()
+    }
+
+    /// A user with query 0 "moog" last run at `dtime(0)` that has seen listing 1.
+    fn setup(store: &mut dyn Store) -> MuusSearch {
+        let user = DBUser { chat_id: 1, user_id: "user".to_string(), queries: Vec::new(), timezone: DEFAULT_TIMEZONE };
+        store.put_user(&user).unwrap();
+        store.put_query(1, &DBQuery::new(0, "moog")).unwrap();
+        let ms = &due_searches(store, &Config::default(), dtime(0))[0];
+        apply_results(store, ms, vec![listing(1)]).unwrap();
+        due_searches(store, &Config::default(), dtime(3600)).remove(0)
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("15m"), Ok(900));
+        assert_eq!(parse_interval("4h"), Ok(14400));
+        assert_eq!(parse_interval("1d"), Ok(86400));
+        assert_eq!(parse_interval("90"), Ok(90));
+        for text in ["1w", "h", "-1h", "1.5h", "99999999999999999999d", "<b>"] {
+            assert!(parse_interval(text).is_err(), "{text}");
+        }
+        assert_eq!(parse_interval("1x<").unwrap_err(), "Incorrect interval: <b>1x&lt;</b>.");
+
+        assert_eq!(format_interval(900), "15m");
+        assert_eq!(format_interval(129600), "1d 12h");
+        assert_eq!(format_interval(3661), "1h 1m 1s");
+        assert_eq!(format_interval(0), "0s");
+    }
+
+    #[test]
+    fn filters_parsed() {
+        let filters = MuusFilters { kind: Some(MuusListingKind::Selling), price_max: Some(300),
+                                    region: Some("uusimaa".to_string()), ..MuusFilters::default() };
+        assert!(matches!(parse_command("/add keylab --type=myydaan --max=300 --region=uusimaa"),
+                         TgCommand::Add(text, f) if text == "keylab" && f == filters));
+        assert!(matches!(parse_command("/add moog+sub 37"), TgCommand::Add(text, f)
+                         if text == "moog+sub+37" && f == MuusFilters::default()));
+        assert!(matches!(parse_command("/add a<b --max=300"), TgCommand::Add(text, _) if text == "a<b"));
+
+        // The input is quoted in the HTML replies
+        assert_eq!(parse_filters(&["--max=<300"]).unwrap_err(), "Incorrect price: <b>&lt;300</b>.");
+        assert_eq!(parse_filters(&["--type=<b>"]).unwrap_err(),
+                   "Incorrect type, use <b>myydaan</b> or <b>ostetaan</b>: <b>&lt;b&gt;</b>.");
+        assert_eq!(parse_filters(&["--x&y"]).unwrap_err(), "Unknown option: <b>--x&amp;y</b>.");
+        assert_eq!(parse_local_filter(&["--min=a<"]).unwrap_err(), "Incorrect price: <b>a&lt;</b>.");
+    }
+
+    #[test]
+    fn malformed_indices() {
+        for command in ["/edits x<", "/pause x<", "/interval x< 4h", "/filter x<", "/remove 0 x<"] {
+            assert!(matches!(parse_command(command), TgCommand::Invalid(err) if err == "Incorrect index: <b>x&lt;</b>."),
+                    "{command}");
+        }
+        for command in ["/edits", "/pause", "/interval", "/filter"] {
+            assert!(matches!(parse_command(command), TgCommand::Invalid(err) if err == "Specify the query index."),
+                    "{command}");
+        }
+        assert!(matches!(parse_command("/edits 0"), TgCommand::Invalid(err) if err.contains("<b>on</b> or <b>off</b>")));
+        assert!(matches!(parse_command("/edits 0 maybe"), TgCommand::Invalid(_)));
+    }
+
+    #[test]
+    fn custom_interval_due() {
+        let mut store = store("interval");
+        setup(&mut store);
+        // Last run at dtime(0), the configured interval is an hour
+        let config = Config::default();
+        assert!(due_searches(&store, &config, dtime(900)).is_empty());
+
+        let q = store.user(1).unwrap().unwrap().queries.remove(0);
+        store.put_query(1, &DBQuery { interval: Some(900), ..q }).unwrap();
+        assert!(due_searches(&store, &config, dtime(899)).is_empty());
+        assert_eq!(due_searches(&store, &config, dtime(900)).len(), 1);
+
+        // Limits changed since the interval was set still apply
+        let config = Config { interval_min: 1800, ..Config::default() };
+        assert!(due_searches(&store, &config, dtime(900)).is_empty());
+        assert_eq!(due_searches(&store, &config, dtime(1800)).len(), 1);
+    }
+
+    #[test]
+    fn results_applied() {
+        let mut store = store("applied");
+        let ms = setup(&mut store);
+        assert_eq!(ms.seen_ids, HashSet::from([1]));
+
+        let (news, _) = apply_results(&mut store, &ms, vec![listing(2), listing(1)]).unwrap().unwrap();
+        assert!(matches!(news.as_slice(), [ListingNews::New(l)] if l.id == 2));
+        let q = store.user(1).unwrap().unwrap().queries.remove(0);
+        assert_eq!(q.dtime_prev_req, Some(dtime(3600)));
+        assert_eq!(q.seen.len(), 2);
+    }
+
+    #[test]
+    fn imported_rule_applied_once() {
+        let mut store = store("imported");
+        let user = DBUser { chat_id: 1, user_id: "user".to_string(), queries: Vec::new(), timezone: DEFAULT_TIMEZONE };
+        store.put_user(&user).unwrap();
+        let q = DBQuery { dtime_prev_req: Some(dtime(0)), imported: true, ..DBQuery::new(0, "moog") };
+        store.put_query(1, &q).unwrap();
+        let old = |id: u64| MuusListing { dtime_add: dtime(-60), dtime_upd: dtime(-60), ..listing(id) };
+
+        // Only what changed since the Python bot's last run is news
+        let ms = due_searches(&store, &Config::default(), dtime(3600)).remove(0);
+        let (news, _) = apply_results(&mut store, &ms, vec![listing(2), old(1)]).unwrap().unwrap();
+        assert!(matches!(news.as_slice(), [ListingNews::New(l)] if l.id == 2));
+        assert!(!store.user(1).unwrap().unwrap().queries[0].imported);
+
+        // Later on, every listing not seen yet is
+        let ms = due_searches(&store, &Config::default(), dtime(7200)).remove(0);
+        let (news, _) = apply_results(&mut store, &ms, vec![old(3), listing(2), old(1)]).unwrap().unwrap();
+        assert!(matches!(news.as_slice(), [ListingNews::New(l)] if l.id == 3));
+
+        // As for native queries whose first run found nothing
+        store.put_query(1, &DBQuery::new(1, "korg")).unwrap();
+        let ms = due_searches(&store, &Config::default(), dtime(7200)).remove(0);
+        assert_eq!(apply_results(&mut store, &ms, Vec::new()).unwrap().unwrap().0.len(), 0);
+        let ms = due_searches(&store, &Config::default(), dtime(10800)).remove(1);
+        let (news, _) = apply_results(&mut store, &ms, vec![old(4)]).unwrap().unwrap();
+        assert!(matches!(news.as_slice(), [ListingNews::New(l)] if l.id == 4));
+    }
+
+    #[test]
+    fn edits_reported() {
+        let mut store = store("edits");
+        let ms = setup(&mut store);
+        let edited = MuusListing { title: "Listing 1, price cut".to_string(), price: Some(400), text_hash: 11,
+                                   ..listing(1) };
+        let (news, timezone) = apply_results(&mut store, &ms, vec![edited.clone()]).unwrap().unwrap();
+        let [ListingNews::Edited { listing: l, title_prev, price_prev }] = news.as_slice() else {
+            panic!("not a single edit");
+        };
+        assert_eq!((l.id, title_prev.as_str(), *price_prev), (1, "Listing 1", None));
+        let text = compose_news("moog", &news[0], None, timezone);
+        assert!(text.contains("Title: Listing 1 \u{2192} Listing 1, price cut"), "{text}");
+        assert!(text.contains("Price: - \u{2192} 400 €"), "{text}");
+
+        // Reported once
+        let ms = due_searches(&store, &Config::default(), dtime(7200)).remove(0);
+        let (news, _) = apply_results(&mut store, &ms, vec![edited.clone()]).unwrap().unwrap();
+        assert!(news.is_empty());
+
+        // "/edits 0 off" still records the state, so turning them on again does not report old edits
+        let q = store.user(1).unwrap().unwrap().queries.remove(0);
+        store.put_query(1, &DBQuery { notify_edits: false, ..q }).unwrap();
+        let ms = due_searches(&store, &Config::default(), dtime(10800)).remove(0);
+        let edited = MuusListing { text_hash: 12, ..edited };
+        let (news, _) = apply_results(&mut store, &ms, vec![edited, listing(2)]).unwrap().unwrap();
+        assert!(matches!(news.as_slice(), [ListingNews::New(l)] if l.id == 2));
+        let q = store.user(1).unwrap().unwrap().queries.remove(0);
+        assert_eq!(q.seen.iter().find(|s| s.id == 1).unwrap().text_hash, Some(12));
+    }
+
+    #[test]
+    fn results_of_changed_queries_dropped() {
+        let mut store = store("changed");
+        let ms = setup(&mut store);
+        let q = store.user(1).unwrap().unwrap().queries.remove(0);
+
+        // Edited during the search
+        store.put_query(1, &DBQuery { text: "moog+sub".to_string(), ..q.clone() }).unwrap();
+        assert!(apply_results(&mut store, &ms, vec![listing(2)]).unwrap().is_none());
+        store.put_query(1, &DBQuery { paused: true, ..q.clone() }).unwrap();
+        assert!(apply_results(&mut store, &ms, vec![listing(2)]).unwrap().is_none());
+        let q_now = store.user(1).unwrap().unwrap().queries.remove(0);
+        assert_eq!(q_now.dtime_prev_req, Some(dtime(0)));
+        assert_eq!(q_now.seen.len(), 1);
+
+        // Removed, or removed and added again
+        store.remove_query(1, 0).unwrap();
+        assert!(apply_results(&mut store, &ms, vec![listing(2)]).unwrap().is_none());
+        store.put_query(1, &DBQuery { dtime_prev_req: None, ..q }).unwrap();
+        assert!(apply_results(&mut store, &ms, vec![listing(2)]).unwrap().is_none());
+        store.remove_user(1).unwrap();
+        assert!(apply_results(&mut store, &ms, vec![listing(2)]).unwrap().is_none());
+    }
+
+    struct Market {
+        base_url: String,
+        n_searches: Arc<AtomicUsize>,
+        n_details: Arc<AtomicUsize>,  // listing pages served
+    }
+
+    /// Serves the fixture `pages` to the searches in turn, repeating the last one, and a page for
+    /// every listing.
+    fn serve_market(pages: Vec<&'static str>) -> Market {
+        let (n_searches, n_details) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (searches, details) = (Arc::clone(&n_searches), Arc::clone(&n_details));
+        let base_url = serve_with(move |url, _| {
+            if url.starts_with("/tori/haku.php") {
+                let n = searches.fetch_add(1, Ordering::SeqCst);
+                (200, fixture(pages[n.min(pages.len() - 1)]))
+            } else if let Some(id) = url.strip_prefix("/tori/ilmoitus/") {
+                details.fetch_add(1, Ordering::SeqCst);
+                (200, format!("<html><body><table><tr><td class=\"tori_title\">Listing {id}</td></tr>\
+                               <tr><td class=\"tori_text\">Barely used, comes with the original box.</td></tr>\
+                               </table></body></html>"))
+            } else {
+                (404, String::new())
+            }
+        });
+        Market { base_url, n_searches, n_details }
+    }
+
+    fn message(chat_id: i64, text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 1718000000,
+            "chat": {"id": chat_id, "type": "private", "username": format!("user{chat_id}")},
+            "text": text,
+        })).unwrap()
+    }
+
+    struct Bot {
+        state: Arc<Mutex<SharedState>>,
+        messenger: Arc<MemoryMessenger>,
+        muus: Arc<MuusClient>,
+        config: Arc<Config>,
+    }
+
+    impl Bot {
+        fn new(name: &str, base_url: &str) -> Bot {
+            let config = Config { base_url: base_url.to_string(), requests_per_sec: 100.0, ..Config::default() };
+            Bot {
+                state: Arc::new(Mutex::new(SharedState { store: Box::new(store(name)) })),
+                messenger: Arc::new(MemoryMessenger::default()),
+                muus: Arc::new(MuusClient::new(base_url, config.muus_settings())),
+                config: Arc::new(config),
+            }
+        }
+
+        /// Sends the text as the user of the chat and returns the response.
+        fn send(&self, chat_id: i64, text: &str) -> String {
+            task_repl(self.state.clone(), self.messenger.clone(), self.muus.clone(), self.config.clone(),
+                      message(chat_id, text));
+            let mut messages = self.messenger.take_messages();
+            assert_eq!(messages.len(), 1, "{messages:?}");
+            let smp = messages.remove(0);
+            assert_eq!(smp.chat_id, ChatId::Integer(chat_id));
+            smp.text
+        }
+
+        fn run_searches(&self, dtime_now: DateTime<Utc>) -> Vec<SendMessageParams> {
+            run_searches(&self.state, self.messenger.as_ref(), &self.muus, &self.config, dtime_now);
+            self.messenger.take_messages()
+        }
+    }
+
+    #[test]
+    fn start_add_search_notify() {
+        let Market { base_url, n_searches, n_details } = serve_market(vec!["buy_sell.html", "single_page.html"]);
+        let bot = Bot::new("flow", &base_url);
+
+        assert!(bot.send(1, "/add moog sub").contains("create an account first"));
+        assert!(bot.send(1, "/start").contains("Welcome to the service!"));
+        assert!(bot.send(1, "/add moog sub").contains("Query added: <b>moog+sub</b>"));
+        assert!(bot.send(2, "/start").contains("<b>user2</b>"));
+        assert!(bot.send(2, "/add Moog Sub").contains("Query added"));
+
+        // The first run only records what is there
+        let dtime_now = Utc::now();
+        assert!(bot.run_searches(dtime_now).is_empty());
+        assert_eq!(n_searches.load(Ordering::SeqCst), 1);
+        assert!(bot.run_searches(dtime_now + TimeDelta::minutes(10)).is_empty());
+        assert_eq!(n_searches.load(Ordering::SeqCst), 1);
+
+        let sent = bot.run_searches(dtime_now + TimeDelta::hours(1));
+        assert_eq!(n_searches.load(Ordering::SeqCst), 2);
+        assert_eq!(sent.len(), 6);
+        // Once per listing, not per user
+        assert_eq!(n_details.load(Ordering::SeqCst), 3);
+        for chat_id in [1, 2] {
+            let texts: Vec<&str> = sent.iter()
+                .filter(|smp| smp.chat_id == ChatId::Integer(chat_id))
+                .map(|smp| smp.text.as_str())
+                .collect();
+            assert_eq!(texts.len(), 3);
+            assert!(texts.iter().all(|t| t.contains("Barely used, comes with the original box.")), "{texts:?}");
+            assert!(texts.iter().any(|t| t.contains(&format!("{base_url}/tori/ilmoitus/3401240"))), "{texts:?}");
+        }
+        let Some(ReplyMarkup::InlineKeyboardMarkup(markup)) = &sent[0].reply_markup else { panic!("no button") };
+        let data = markup.inline_keyboard[0][0].callback_data.clone().unwrap();
+        assert!(data.starts_with("detail:/tori/ilmoitus/"), "{data}");
+
+        let detail = bot.send(1, &format!("/show {base_url}/tori/ilmoitus/3401240"));
+        assert!(detail.starts_with("<b>Listing 3401240</b>"), "{detail}");
+        assert!(bot.send(1, "/show https://example.com/tori/x").contains("Not a listing link"));
+
+        let history = bot.send(1, "/history");
+        assert!(history.contains("3401240"), "{history}");
+        assert!(bot.run_searches(dtime_now + TimeDelta::hours(2)).is_empty());
+
+        // "Details" button
+        let update: Update = serde_json::from_str(&fs::read_to_string(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/update_callback.json")).unwrap()).unwrap();
+        task_update(bot.state.clone(), bot.messenger.clone(), bot.muus.clone(), bot.config.clone(), update);
+        let sent = bot.messenger.take_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].chat_id, ChatId::Integer(111));
+        assert!(sent[0].text.starts_with("<b>Listing 1234567</b>"), "{}", sent[0].text);
+        assert_eq!(bot.messenger.answers.lock().unwrap().len(), 1);
+
+        // Spoofed to point at another host
+        let mut update: Update = serde_json::from_str(&fs::read_to_string(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/update_callback.json")).unwrap()).unwrap();
+        let UpdateContent::CallbackQuery(callback_query) = &mut update.content else { panic!("not a callback") };
+        let host = base_url.trim_start_matches("http://");
+        callback_query.data = Some(format!("detail:@{host}/tori/ilmoitus/1"));
+        task_update(bot.state.clone(), bot.messenger.clone(), bot.muus.clone(), bot.config.clone(), update);
+        assert!(bot.messenger.take_messages().is_empty());
+        assert_eq!(bot.messenger.answers.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stopped_user_not_notified() {
+        let base_url = serve_market(vec!["buy_sell.html", "single_page.html"]).base_url;
+        let bot = Bot::new("stopped", &base_url);
+        bot.send(1, "/start");
+        bot.send(1, "/add moog");
+        bot.send(1, "/pause 0");
+        let dtime_now = Utc::now();
+        assert!(bot.run_searches(dtime_now).is_empty());
+
+        bot.send(1, "/resume 0");
+        assert!(bot.run_searches(dtime_now).is_empty());
+        bot.send(1, "/stop");
+        assert!(bot.run_searches(dtime_now + TimeDelta::hours(1)).is_empty());
+        assert!(bot.state.lock().unwrap().store.users().unwrap().is_empty());
+    }
+
+    #[test]
+    fn interval_limits_shown() {
+        let mut bot = Bot::new("interval_limits", "http://127.0.0.1:9");
+        bot.send(1, "/start");
+        bot.send(1, "/add moog");
+        assert!(bot.send(1, "/interval 0 15m").contains("runs every <b>15m</b>"));
+        assert!(bot.send(1, "/list").contains("[every 15m]"));
+
+        // Raised by the admin afterwards
+        bot.config = Arc::new(Config { interval_min: 1800, ..(*bot.config).clone() });
+        assert!(bot.send(1, "/list").contains("[every 30m]"));
+    }
+
+    #[test]
+    fn unusual_messages_ignored() {
+        let base_url = serve_market(vec!["no_results.html"]).base_url;
+        let bot = Bot::new("unusual", &base_url);
+        let update = |message: serde_json::Value| -> Update {
+            serde_json::from_value(serde_json::json!({"update_id": 1, "message": message})).unwrap()
+        };
+        let handle = |update| task_update(bot.state.clone(), bot.messenger.clone(), bot.muus.clone(),
+                                          bot.config.clone(), update);
+
+        // A photo
+        handle(update(serde_json::json!({
+            "message_id": 1, "date": 1718000000,
+            "chat": {"id": 1, "type": "private", "username": "user1"},
+            "photo": [{"file_id": "x", "file_unique_id": "y", "width": 90, "height": 90}],
+        })));
+        // A user without a username
+        handle(update(serde_json::json!({
+            "message_id": 2, "date": 1718000000,
+            "chat": {"id": 1, "type": "private", "first_name": "Anon"},
+            "text": "/start",
+        })));
+        assert!(bot.messenger.take_messages().is_empty());
+        assert!(bot.state.lock().unwrap().store.users().unwrap().is_empty());
+
+        // Still handled afterwards
+        assert!(bot.send(1, "/start").contains("Welcome"));
+        assert!(bot.send(1, "/remove abc").contains("Incorrect index: <b>abc</b>"));
+        assert!(bot.send(1, "/remove 0 x<").contains("Incorrect index: <b>x&lt;</b>"));
+
+        // Keywords with markup are quoted in every reply
+        assert!(bot.send(1, "/add a<b").contains("Query added: <b>a&lt;b</b>."));
+        assert!(bot.send(1, "/add a<b").contains("Query already exists: <b>a&lt;b</b>."));
+        assert!(bot.send(1, "/list").contains("[0]: <b>a&lt;b</b>"));
+        for command in ["/edits 0 off", "/pause 0", "/resume 0", "/interval 0 1h", "/filter 0", "/remove 0"] {
+            let response = bot.send(1, command);
+            assert!(response.contains("(<b>a&lt;b</b>)") && !response.contains("a<b"), "{response}");
         }
     }
 }
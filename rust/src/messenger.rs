@@ -0,0 +1,130 @@
+use std::error::Error;
+use std::fmt;
+use frankenstein::{AnswerCallbackQueryParams, Api, EditMessageTextParams, GetUpdatesParams, SendMessageParams,
+                   TelegramApi, Update};
+
+
+#[derive(Debug)]
+pub(crate) struct MessengerError(String);
+
+impl fmt::Display for MessengerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for MessengerError {}
+
+impl From<frankenstein::Error> for MessengerError {
+    fn from(err: frankenstein::Error) -> Self { MessengerError(format!("{err:?}")) }
+}
+
+/// The part of the Telegram Bot API used by the bot.
+pub(crate) trait Messenger: Send + Sync {
+    /// Returns the ID of the sent message.
+    fn send_message(&self, params: &SendMessageParams) -> Result<i32, MessengerError>;
+    #[allow(dead_code)]  // notifications are not updated after sending yet
+    fn edit_message(&self, params: &EditMessageTextParams) -> Result<(), MessengerError>;
+    fn answer_callback(&self, params: &AnswerCallbackQueryParams) -> Result<(), MessengerError>;
+    fn get_updates(&self, params: &GetUpdatesParams) -> Result<Vec<Update>, MessengerError>;
+}
+
+impl Messenger for Api {
+    fn send_message(&self, params: &SendMessageParams) -> Result<i32, MessengerError> {
+        Ok(TelegramApi::send_message(self, params)?.result.message_id)
+    }
+
+    fn edit_message(&self, params: &EditMessageTextParams) -> Result<(), MessengerError> {
+        self.edit_message_text(params)?;
+        Ok(())
+    }
+
+    fn answer_callback(&self, params: &AnswerCallbackQueryParams) -> Result<(), MessengerError> {
+        self.answer_callback_query(params)?;
+        Ok(())
+    }
+
+    fn get_updates(&self, params: &GetUpdatesParams) -> Result<Vec<Update>, MessengerError> {
+        Ok(TelegramApi::get_updates(self, params)?.result)
+    }
+}
+
+/// Keeps the requests of the bot in memory instead of sending them, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryMessenger {
+    pub messages: std::sync::Mutex<Vec<SendMessageParams>>,
+    pub edits: std::sync::Mutex<Vec<EditMessageTextParams>>,
+    pub answers: std::sync::Mutex<Vec<AnswerCallbackQueryParams>>,
+    pub updates: std::sync::Mutex<Vec<Update>>,  // pending, until confirmed with the offset as by Telegram
+}
+
+#[cfg(test)]
+impl MemoryMessenger {
+    /// Removes and returns the messages sent so far.
+    pub fn take_messages(&self) -> Vec<SendMessageParams> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl Messenger for MemoryMessenger {
+    fn send_message(&self, params: &SendMessageParams) -> Result<i32, MessengerError> {
+        let mut messages = self.messages.lock().unwrap();
+        messages.push(params.clone());
+        Ok(messages.len() as i32)
+    }
+
+    fn edit_message(&self, params: &EditMessageTextParams) -> Result<(), MessengerError> {
+        self.edits.lock().unwrap().push(params.clone());
+        Ok(())
+    }
+
+    fn answer_callback(&self, params: &AnswerCallbackQueryParams) -> Result<(), MessengerError> {
+        self.answers.lock().unwrap().push(params.clone());
+        Ok(())
+    }
+
+    fn get_updates(&self, params: &GetUpdatesParams) -> Result<Vec<Update>, MessengerError> {
+        let mut updates = self.updates.lock().unwrap();
+        if let Some(offset) = params.offset {
+            updates.retain(|u| i64::from(u.update_id) >= offset);
+        }
+        Ok(updates.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frankenstein::ChatId;
+
+    fn update(update_id: u32) -> Update {
+        serde_json::from_value(serde_json::json!({
+            "update_id": update_id,
+            "message": {"message_id": 1, "date": 1718000000, "chat": {"id": 1, "type": "private"}, "text": "/list"},
+        })).unwrap()
+    }
+
+    #[test]
+    fn memory_messenger() {
+        let messenger = MemoryMessenger::default();
+        messenger.updates.lock().unwrap().extend([update(7), update(8)]);
+        let params = GetUpdatesParams::builder().build();
+        assert_eq!(messenger.get_updates(&params).unwrap().len(), 2);
+        // Confirmed by the offset, as with Telegram
+        let params = GetUpdatesParams::builder().offset(8).build();
+        let ids: Vec<u32> = messenger.get_updates(&params).unwrap().iter().map(|u| u.update_id).collect();
+        assert_eq!(ids, vec![8]);
+
+        let smp = SendMessageParams::builder().chat_id(ChatId::Integer(1)).text("first").build();
+        assert_eq!(messenger.send_message(&smp).unwrap(), 1);
+        assert_eq!(messenger.send_message(&smp).unwrap(), 2);
+        let emtp = EditMessageTextParams::builder().chat_id(ChatId::Integer(1)).message_id(2).text("second").build();
+        messenger.edit_message(&emtp).unwrap();
+        assert_eq!(messenger.take_messages().len(), 2);
+        assert!(messenger.take_messages().is_empty());
+        assert_eq!(messenger.edits.lock().unwrap()[0].text, "second");
+    }
+}
@@ -1,11 +1,88 @@
+use std::collections::HashSet;
 use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use select::document::Document;
-use select::predicate::Attr;
-use regex::Regex;
+use select::node::Node;
+use select::predicate::{Attr, Name};
+use regex::{Regex, RegexBuilder};
 use chrono::prelude::*;
+use chrono::{LocalResult, TimeDelta};
+use chrono_tz::Europe::Helsinki;
+use serde::{Deserialize, Serialize};
 use url::form_urlencoded;
 
 
+static RE_HREF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"href="([^"]+)""#).unwrap());
+static RE_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"/tori/\D*(\d+)"#).unwrap());
+static RE_ADD: LazyLock<Regex> = LazyLock::new(
+    || Regex::new(r#"Lisätty: (\d{2}).(\d{2}).(\d{4}) (\d{2}):(\d{2})"#).unwrap());
+static RE_UPD: LazyLock<Regex> = LazyLock::new(
+    || Regex::new(r#"Muokattu: (\d{2}).(\d{2}).(\d{4}) (\d{2}):(\d{2})"#).unwrap());
+static RE_PRICE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"Hinta:\s*(\d[\d\s]*?)\s*€"#).unwrap());
+static RE_NEXT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"<a href="([^"]+)">seuraava</a>"#).unwrap());
+
+#[derive(Debug)]
+pub(crate) enum MuusError {
+    Network(reqwest::Error),
+    HttpStatus { url: String, status: u16 },
+    Parse { reason: String, row: String },  // with the HTML of the offending row
+    Pagination(String),  // with the "seuraava" (next) link
+}
+
+impl fmt::Display for MuusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MuusError::Network(err) => write!(f, "network error: {}", err),
+            MuusError::HttpStatus { url, status } => write!(f, "HTTP status {} from {}", status, url),
+            MuusError::Parse { reason, row } => write!(f, "failed to parse {} in row: {}", reason, row),
+            MuusError::Pagination(link) => write!(f, "failed to follow the next page link: {}", link),
+        }
+    }
+}
+
+impl std::error::Error for MuusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MuusError::Network(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for MuusError {
+    fn from(err: reqwest::Error) -> Self {
+        MuusError::Network(err)
+    }
+}
+
+/// HTTP client of the marketplace, e.g. of https://muusikoiden.net or of a local mock server.
+/// Shared by all the tasks, so that the rate limit applies to the whole bot.
+pub(crate) struct MuusClient {
+    base_url: String,
+    client: reqwest::blocking::Client,
+    settings: MuusClientSettings,
+    bucket: Mutex<TokenBucket>,
+}
+
+/// Built from the config, see [`Config::muus_settings`](crate::config::Config::muus_settings).
+#[derive(Clone, Debug)]
+pub(crate) struct MuusClientSettings {
+    pub user_agent: String,
+    pub timeout: Duration,
+    pub requests_per_sec: f64,
+    pub max_retries: u32,  // on connection errors and 5xx/429 responses
+    pub backoff: Duration,  // before the first retry, doubled for each next one
+    pub max_pages: usize,  // of results to fetch per search
+}
+
+/// Rate limiter allowing bursts of a single request.
+struct TokenBucket {
+    tokens: f64,
+    dtime_refill: Instant,
+}
+
 #[derive(Clone)]
 pub(crate) struct MuusSearch {
     pub user_id: String,
@@ -13,11 +90,205 @@ pub(crate) struct MuusSearch {
     pub uniq_id: u64,
     pub text: String,
     pub dtime_req: DateTime<Utc>,
+    pub dtime_prev_req: Option<DateTime<Utc>>,  // None if the query has never been run
+    pub seen_ids: HashSet<u64>,  // listings already returned by the previous runs
+    pub filters: MuusFilters,
+    pub imported: bool,  // the first run of a query imported from the Python bot or an older db
+}
+
+/// Search options besides the keywords, as in the form of haku.php.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct MuusFilters {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_min: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_max: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MuusListingKind>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub titles_only: bool,
+}
+
+impl MuusFilters {
+    /// Parameters of haku.php for the filters that are set.
+    fn to_query(&self) -> Vec<(String, String)> {
+        let mut query: Vec<(String, String)> = Vec::new();
+        if let Some(category) = &self.category { query.push(("category".to_string(), category.clone())); }
+        if let Some(region) = &self.region { query.push(("location".to_string(), region.clone())); }
+        if let Some(price_min) = self.price_min { query.push(("price_min".to_string(), price_min.to_string())); }
+        if let Some(price_max) = self.price_max { query.push(("price_max".to_string(), price_max.to_string())); }
+        if let Some(kind) = self.kind { query.push(("type".to_string(), kind.to_param().to_string())); }
+        if self.titles_only { query.push(("titles_only".to_string(), "1".to_string())); }
+        query
+    }
+}
+
+/// Short form used in the bot, e.g. "type=myydaan, max=300 €, region=uusimaa".
+impl fmt::Display for MuusFilters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(kind) = self.kind { parts.push(format!("type={}", kind.to_param())); }
+        if let Some(price_min) = self.price_min { parts.push(format!("min={} €", price_min)); }
+        if let Some(price_max) = self.price_max { parts.push(format!("max={} €", price_max)); }
+        if let Some(region) = &self.region { parts.push(format!("region={}", region)); }
+        if let Some(category) = &self.category { parts.push(format!("category={}", category)); }
+        if self.titles_only { parts.push("titles only".to_string()); }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl MuusSearch {
+    /// Whether every listing on the page is already seen or not modified since the previous run.
+    fn is_known_page(&self, listings: &[MuusListing]) -> bool {
+        !listings.is_empty() && listings.iter().all(|l| {
+            self.seen_ids.contains(&l.id) || self.dtime_prev_req.is_some_and(|dtime| l.dtime_upd <= dtime)
+        })
+    }
+}
+
+/// Identical searches of several queries, to be fetched once.
+pub(crate) struct MuusSearchGroup {
+    pub search: MuusSearch,  // stops no earlier than any of the members would
+    pub members: Vec<MuusSearch>,
+}
+
+/// Keywords of the search as the site treats them, e.g. "Moog++Sub" and "moog+sub" are the same.
+fn normalize_text(text: &str) -> String {
+    text.split('+').filter(|w| !w.is_empty()).map(|w| w.to_lowercase()).collect::<Vec<String>>().join("+")
+}
+
+/// Groups the searches with the same keywords and filters, keeping the order of their first appearance.
+pub(crate) fn group_searches(searches: Vec<MuusSearch>) -> Vec<MuusSearchGroup> {
+    let mut groups: Vec<MuusSearchGroup> = Vec::new();
+    for ms in searches {
+        let text = normalize_text(&ms.text);
+        match groups.iter_mut().find(|g| g.search.text == text && g.search.filters == ms.filters) {
+            Some(group) => {
+                // A page is known to the group only if it is known to every member
+                let search = &mut group.search;
+                search.seen_ids.retain(|id| ms.seen_ids.contains(id));
+                search.dtime_prev_req = search.dtime_prev_req.zip(ms.dtime_prev_req).map(|(a, b)| a.min(b));
+                search.dtime_req = search.dtime_req.min(ms.dtime_req);
+                group.members.push(ms);
+            }
+            None => {
+                let search = MuusSearch { text, ..ms.clone() };
+                groups.push(MuusSearchGroup { search, members: vec![ms] });
+            }
+        }
+    }
+    groups
+}
+
+/// Rules applied to the results locally, to drop what the fuzzy search of the site lets through.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub(crate) struct MuusLocalFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub require: Vec<String>,  // words that must all be in the title
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,  // words that must not be in the title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title_regex: Option<String>,  // matched case-insensitively
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_min: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_max: Option<u32>,
+}
+
+impl MuusLocalFilter {
+    pub fn compile_regex(pattern: &str) -> Result<Regex, regex::Error> {
+        RegexBuilder::new(pattern).case_insensitive(true).build()
+    }
+
+    /// The `title_regex` compiled for [`MuusLocalFilter::matches`], `None` if there is none.
+    ///
+    /// The pattern is validated when set, so an invalid one is treated as none and lets everything through.
+    pub fn title_re(&self) -> Option<Regex> {
+        self.title_regex.as_deref().and_then(|pattern| MuusLocalFilter::compile_regex(pattern).ok())
+    }
+
+    /// Whether the listing passes all the rules, with `title_re` compiled once for all the listings
+    /// by [`MuusLocalFilter::title_re`]. Listings without a price pass the price bounds.
+    pub fn matches(&self, listing: &MuusListing, title_re: Option<&Regex>) -> bool {
+        let title = listing.title.to_lowercase();
+        if !self.require.iter().all(|w| title.contains(&w.to_lowercase())) {
+            return false;
+        }
+        if self.exclude.iter().any(|w| title.contains(&w.to_lowercase())) {
+            return false;
+        }
+        if title_re.is_some_and(|re| !re.is_match(&listing.title)) {
+            return false;
+        }
+        if let Some(price) = listing.price {
+            if self.price_min.is_some_and(|p| price < p) || self.price_max.is_some_and(|p| price > p) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Short form used in the bot, e.g. "+digitakt, -rikki, /mk\s?ii/, max=300 €".
+impl fmt::Display for MuusLocalFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = Vec::new();
+        parts.extend(self.require.iter().map(|w| format!("+{}", w)));
+        parts.extend(self.exclude.iter().map(|w| format!("-{}", w)));
+        if let Some(pattern) = &self.title_regex { parts.push(format!("/{}/", pattern)); }
+        if let Some(price_min) = self.price_min { parts.push(format!("min={} €", price_min)); }
+        if let Some(price_max) = self.price_max { parts.push(format!("max={} €", price_max)); }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum MuusListingKind {
+    Selling,  // "Myydään"
+    Buying,  // "Ostetaan"
+}
+
+impl MuusListingKind {
+    /// Value of the listing type in haku.php.
+    pub fn to_param(self) -> &'static str {
+        match self {
+            MuusListingKind::Selling => "myydaan",
+            MuusListingKind::Buying => "ostetaan",
+        }
+    }
+
+    pub fn from_param(param: &str) -> Option<MuusListingKind> {
+        match param {
+            "myydaan" | "myydään" | "selling" => Some(MuusListingKind::Selling),
+            "ostetaan" | "buying" => Some(MuusListingKind::Buying),
+            _ => None,
+        }
+    }
 }
 
+impl fmt::Display for MuusListingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MuusListingKind::Selling => write!(f, "Selling"),
+            MuusListingKind::Buying => write!(f, "Buying"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct MuusListing {
+    pub id: u64,
     pub title: String,
     pub url: String,
+    pub kind: Option<MuusListingKind>,
+    pub price: Option<u32>,  // in euros
+    pub region: Option<String>,
+    pub seller: Option<String>,
+    pub text_hash: u64,  // of the whole listing text, to detect edits
     pub dtime_add: DateTime<Utc>,
     pub dtime_upd: DateTime<Utc>,
 }
@@ -25,109 +296,709 @@ pub(crate) struct MuusListing {
 impl fmt::Display for MuusListing {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{} | {} | {} | {}", self.title, self.url,
-               self.dtime_add.format("%Y-%m-%d %H:%M:%S").to_string(),
-               self.dtime_add.format("%Y-%m-%d %H:%M:%S").to_string())
+               self.dtime_add.format("%Y-%m-%d %H:%M:%S"),
+               self.dtime_upd.format("%Y-%m-%d %H:%M:%S"))
     }
 }
 
-fn page_to_listings(document: Document) -> Vec<MuusListing> {
-    let mut listings: Vec<MuusListing> = Vec::new();
+pub(crate) struct MuusListingDetail {
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    pub image_urls: Vec<String>,
+    pub seller: Option<String>,
+    pub contact: Option<String>,
+    pub dtime_add: Option<DateTime<Utc>>,
+    pub dtime_upd: Option<DateTime<Utc>>,
+}
 
+#[derive(Default)]
+pub(crate) struct MuusResults {
+    pub listings: Vec<MuusListing>,
+    pub rows: usize,  // number of listing rows found, including the malformed ones
+    pub warnings: Vec<MuusError>,  // parse errors of the skipped rows
+}
+
+impl MuusResults {
+    fn extend(&mut self, other: MuusResults) {
+        self.listings.extend(other.listings);
+        self.rows += other.rows;
+        self.warnings.extend(other.warnings);
+    }
+}
+
+/// Collects the table rows of a listing, i.e. its `bg2` header row and the rows up to the next header.
+fn listing_rows(header: Node) -> Vec<Node> {
+    let mut rows = vec![header];
+    let mut next = header.next();
+    while let Some(row) = next {
+        if row.is(Attr("class", "bg2")) {
+            break
+        }
+        if row.name() == Some("tr") {
+            rows.push(row);
+        }
+        next = row.next();
+    }
+    rows
+}
+
+/// FNV-1a, since the hashes are stored in the db and must not change between builds.
+fn text_hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Labels of the fields in the listing text, in the order they appear
+const FIELD_LABELS: [&str; 6] = ["Hinta:", "Paikkakunta:", "Ilmoittaja:", "Yhteydenotto:", "Lisätty:", "Muokattu:"];
+
+/// Extracts the value following `label` in the listing text, up to the next label or separator.
+fn field_value(text: &str, label: &str) -> Option<String> {
+    let start = text.find(label)? + label.len();
+    let rest = &text[start..];
+    let end = FIELD_LABELS.iter()
+        .filter_map(|l| rest.find(l))
+        .chain(rest.find('|'))
+        .min()
+        .unwrap_or(rest.len());
+    let value = rest[..end].trim();
+    if value.is_empty() { None } else { Some(value.to_string()) }
+}
+
+/// Converts the local time of the site to UTC.
+pub(crate) fn helsinki_to_utc(naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+    let dtime = match Helsinki.from_local_datetime(&naive) {
+        LocalResult::Single(dtime) => Some(dtime),
+        // When the clocks go back, the hour repeats. Take the later one not to miss listings at a cutoff
+        LocalResult::Ambiguous(_, latest) => Some(latest),
+        // When the clocks go forward, the hour is skipped, so the time is from a clock not yet switched
+        LocalResult::None => Helsinki.from_local_datetime(&(naive + TimeDelta::hours(1))).single(),
+    };
+    dtime.map(|d| d.with_timezone(&Utc))
+}
+
+/// Parses the first "dd.mm.yyyy hh:mm" timestamp captured by `re`, in the Europe/Helsinki time.
+fn parse_dtime(re: &Regex, text: &str) -> Option<DateTime<Utc>> {
+    let (_, ret): (&str, [&str; 5]) = re.captures(text)?.extract();
+    let mut nums = [0u32; 5];
+    for (num, e) in nums.iter_mut().zip(ret) {
+        *num = e.parse::<u32>().ok()?;
+    }
+    let [d, mo, y, h, mi] = nums;
+    let naive = NaiveDate::from_ymd_opt(y as i32, mo, d)?.and_hms_opt(h, mi, 0)?;
+    helsinki_to_utc(naive)
+}
+
+fn row_to_listing(node: Node, base_url: &str) -> Result<MuusListing, MuusError> {
+    let parse_err = |reason: &str| MuusError::Parse { reason: reason.to_string(), row: node.html() };
+
+    let title = node.find(Attr("class", "tori_title")).next().ok_or_else(|| parse_err("title cell"))?;
+    let link = title.find(Attr("href", ())).next().ok_or_else(|| parse_err("title link"))?;
+    let l_title = link.text();
+    // The title cell starts with the listing type, e.g. "Myydään: Elektron Digitakt"
+    let l_kind = match title.text().trim_start() {
+        t if t.starts_with("Myydään") => Some(MuusListingKind::Selling),
+        t if t.starts_with("Ostetaan") => Some(MuusListingKind::Buying),
+        _ => None,
+    };
+
+    let t = link.html();
+    let p = RE_HREF.captures(&t)
+        .map(|c| c.get(1).unwrap().as_str())  // 0th is the matched string
+        .ok_or_else(|| parse_err("link URL"))?;
+    let l_id = RE_ID.captures(p)
+        .and_then(|c| c.get(1).unwrap().as_str().parse::<u64>().ok())
+        .ok_or_else(|| parse_err("listing ID"))?;
+    let l_url = format!("{base_url}{p}");
+
+    let t = node.find(Attr("title", ())).next().ok_or_else(|| parse_err("timestamps"))?.html();
+    let l_dtime_add = parse_dtime(&RE_ADD, &t).unwrap_or_else(Utc::now);
+    // Listings that have never been edited have no "Muokattu" entry
+    let l_dtime_upd = parse_dtime(&RE_UPD, &t).unwrap_or(l_dtime_add);
+
+    let l_text = listing_rows(node).iter()
+        .map(|r| r.text())
+        .collect::<Vec<String>>()
+        .join(" ");
+    let l_text = l_text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let l_text_hash = text_hash(&l_text);
+    let l_price = RE_PRICE.captures(&l_text)
+        .and_then(|c| c.get(1).unwrap().as_str().replace(' ', "").parse::<u32>().ok());
+    let l_region = field_value(&l_text, "Paikkakunta:");
+    let l_seller = field_value(&l_text, "Ilmoittaja:");
+    // println!();
+    // println!("Found: {} {}", node.html(), t);
+    // println!("title {}", l_title);
+    // println!("url {}", l_url);
+    // println!("dtime_add {}", l_dtime_add.format("%Y-%m-%d %H:%M:%S").to_string());
+    // println!("dtime_upd {}", l_dtime_upd.format("%Y-%m-%d %H:%M:%S").to_string());
+    // println!();
+
+    Ok(MuusListing {
+        id: l_id,
+        title: l_title,
+        url: l_url,
+        kind: l_kind,
+        price: l_price,
+        region: l_region,
+        seller: l_seller,
+        text_hash: l_text_hash,
+        dtime_add: l_dtime_add,
+        dtime_upd: l_dtime_upd,
+    })
+}
+
+fn page_to_listings(document: Document, base_url: &str) -> MuusResults {
+    let mut results = MuusResults::default();
+
+    // Malformed rows are skipped, so that a markup change does not hide the rest of the listings
     for node in document.find(Attr("class", "bg2")) {
-        for title in node.find(Attr("class", "tori_title")) {
-            let l_title = title.find(Attr("href", ())).next().unwrap().text();
-
-            let t = title.find(Attr("href", ())).next().unwrap().html();
-            let re = Regex::new(r#"href="([^"]+)""#).unwrap();
-            let p = re.captures(&t).unwrap().get(1).unwrap().as_str();  // 0th is the matched string
-            let l_url = format!("https://muusikoiden.net{p}");
-
-            let t = node.find(Attr("title", ())).next().unwrap().html();
-            let re = Regex::new(r#"Lisätty: (\d{2}).(\d{2}).(\d{4}) (\d{2}):(\d{2})"#).unwrap();
-            let mut l_dtime_add = Utc::now();
-            for (_, ret) in re.captures_iter(&t).map(|c| c.extract()).take(1) {
-                let [d, mo, y, h, mi] = ret.map(|e| e.parse::<u32>().unwrap());
-                l_dtime_add = Utc.with_ymd_and_hms(y as i32, mo, d, h, mi, 0).unwrap();
-            }
+        results.rows += 1;
+        match row_to_listing(node, base_url) {
+            Ok(listing) => results.listings.push(listing),
+            Err(err) => results.warnings.push(err),
+        }
+    }
+    results
+}
 
-            let re = Regex::new(r#"Muokattu: (\d{2}).(\d{2}).(\d{4}) (\d{2}):(\d{2})"#).unwrap();
-            let mut l_dtime_upd = Utc::now();
-            for (_, ret) in re.captures_iter(&t).map(|c| c.extract()).take(1) {
-                let [d, mo, y, h, mi] = ret.map(|e| e.parse::<u32>().unwrap());
-                l_dtime_upd = Utc.with_ymd_and_hms(y as i32, mo, d, h, mi, 0).unwrap();
-            }
-            // println!();
-            // println!("Found: {} {}", node.html(), t);
-            // println!("title {}", l_title);
-            // println!("url {}", l_url);
-            // println!("dtime_add {}", l_dtime_add.format("%Y-%m-%d %H:%M:%S").to_string());
-            // println!("dtime_upd {}", l_dtime_upd.format("%Y-%m-%d %H:%M:%S").to_string());
-            // println!();
-
-            let listing = MuusListing {
-                title: l_title,
-                url: l_url,
-                dtime_add: l_dtime_add,
-                dtime_upd: l_dtime_upd,
+impl MuusClient {
+    pub fn new(base_url: &str, settings: MuusClientSettings) -> MuusClient {
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(settings.user_agent.clone())
+            .timeout(settings.timeout)
+            .build()
+            .unwrap();  // only fails if the TLS backend cannot be initialized, as in Client::new()
+        MuusClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+            settings,
+            bucket: Mutex::new(TokenBucket { tokens: 1.0, dtime_refill: Instant::now() }),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Blocks until the rate limit allows another request.
+    fn wait_turn(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+                let dtime_now = Instant::now();
+                let elapsed = (dtime_now - bucket.dtime_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.settings.requests_per_sec).min(1.0);
+                bucket.dtime_refill = dtime_now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.settings.requests_per_sec)
             };
-            // println!("{}", listing);
-            listings.push(listing);
+            thread::sleep(wait);
+        }
+    }
+
+    /// Wait before the retry after `attempt` failures, the server may ask for a longer one up to the last backoff.
+    fn retry_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = |n: u32| self.settings.backoff.saturating_mul(2u32.saturating_pow(n));
+        let backoff_max = backoff(self.settings.max_retries);
+        match retry_after {
+            Some(retry_after) => retry_after.min(backoff_max),
+            None => backoff(attempt),
         }
     }
-    return listings;
-}
 
-pub(crate) fn search_muus_market(search: MuusSearch) -> Result<Vec<MuusListing>, Box<dyn std::error::Error>> {
-    let mut listings: Vec<MuusListing> = Vec::new();
+    /// Sends the request and returns the page, failing on the unsuccessful HTTP statuses.
+    /// Connection errors, timeouts, and 5xx/429 responses are retried with exponential backoff.
+    fn fetch_page(&self, request: reqwest::blocking::RequestBuilder) -> Result<String, MuusError> {
+        let mut attempt = 0;
+        loop {
+            self.wait_turn();
+            // The requests have no streamed bodies, so they can always be cloned
+            let ret = request.try_clone().unwrap().send();
+            let (err, retry_after) = match ret {
+                Ok(resp) => {
+                    let status = resp.status();
+                    if status.is_success() {
+                        return Ok(resp.text()?);
+                    }
+                    let err = MuusError::HttpStatus { url: resp.url().to_string(), status: status.as_u16() };
+                    if !(status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS) {
+                        return Err(err);
+                    }
+                    let retry_after = resp.headers().get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    (err, retry_after)
+                }
+                Err(err) if err.is_connect() || err.is_timeout() => (MuusError::Network(err), None),
+                Err(err) => return Err(MuusError::Network(err)),
+            };
+
+            if attempt >= self.settings.max_retries {
+                return Err(err);
+            }
+            let delay = self.retry_delay(attempt, retry_after);
+            println!("Request failed, retrying in {delay:?}: {err}");
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
 
-    // First page of the results
-    let client = reqwest::blocking::Client::new();
-    // Encoding is needed to handle "+" correctly
-    let text_enc: String = form_urlencoded::byte_serialize(search.text.as_bytes()).collect();
-    let query_init = [("keyword", text_enc)];
-    let resp: String = client.post("https://muusikoiden.net/tori/haku.php")
-        .query(&query_init).send().unwrap()  // TODO: should handle potential errors here better
-        .text().unwrap();
-    println!("{resp:?}");
+    pub fn search_muus_market(&self, search: MuusSearch) -> Result<MuusResults, MuusError> {
+        let mut results = MuusResults::default();
 
-    let mut resp_str = resp.to_string();
-    let mut page = Document::from(resp_str.as_str());
-    listings.extend(page_to_listings(page.clone()).into_iter());
+        // Encoding is needed to handle "+" correctly
+        let text_enc: String = form_urlencoded::byte_serialize(search.text.as_bytes()).collect();
+        let mut query: Vec<(String, String)> = vec![("keyword".to_string(), text_enc)];
+        query.extend(search.filters.to_query());
+        let mut n_pages = 0;
+        loop {
+            let resp: String = self.fetch_page(self.client.post(format!("{}/tori/haku.php", self.base_url))
+                .query(&query))?;
+            n_pages += 1;
+            let page = page_to_listings(Document::from(resp.as_str()), &self.base_url);
+            // The results are sorted from the newest, so the next pages have nothing new either
+            let is_known = search.is_known_page(&page.listings);
+            results.extend(page);
+            if is_known {
+                break
+            }
 
-    // Additional pages
-    loop {
-        let re = Regex::new(r#"<a href="([^"]+)">seuraava</a>"#).unwrap();
-        if let Some(p) = re.captures(&resp_str) {
+            // Additional pages
+            let Some(p) = RE_NEXT.captures(&resp) else { break };
+            if n_pages >= self.settings.max_pages {
+                println!("Query {} stopped at the limit of {} pages", search.text, n_pages);
+                break
+            }
             let matched = p.get(1).unwrap().as_str();
             // println!("Matched: {}", matched.clone());
 
-            let mut query_cont: Vec<(&str, String)> = Vec::new();
-            let params = matched.split("?").nth(1).unwrap();
+            query.clear();
+            let params = matched.split("?").nth(1).ok_or_else(|| MuusError::Pagination(matched.to_string()))?;
             for param in params.split("&amp;") {
                 if let Some(pos) = param.find("=") {
                     let k = &param[..pos];
                     let v = param[pos + 1..].to_string();
                     if k != "checksum" {
-                        query_cont.push((k, v));
+                        query.push((k.to_string(), v));
                     }
                 }
             }
             // println!("Parsed:");
-            // for (k, v) in &query_cont { println!("{} {}", k.to_string(), v); }
-
-            let resp: String = client.post("https://muusikoiden.net/tori/haku.php")
-                .query(&query_cont).send().unwrap()
-                .text().unwrap();
-            // println!("{resp:?}");
-            resp_str = resp.to_string();
-            page = Document::from(resp_str.as_str());
-            listings.extend(page_to_listings(page.clone()).into_iter());
-        } else {
-            break
+            // for (k, v) in &query { println!("{} {}", k.to_string(), v); }
+        }
+
+        results.listings.reverse();  // Sort in chronological order
+        // println!("Listings found: {}", results.listings.iter().count());
+        Ok(results)
+    }
+
+    /// Downloads a single listing page and extracts its full content.
+    pub fn fetch_listing_detail(&self, url: &str) -> Result<MuusListingDetail, MuusError> {
+        let resp: String = self.fetch_page(self.client.get(url))?;
+        let page = Document::from(resp.as_str());
+
+        let title = page.find(Attr("class", "tori_title")).next()
+            .map(|n| n.text().trim().to_string())
+            .unwrap_or_default();
+        // Keep the line breaks of the description, but not the indentation of the markup
+        let description = page.find(Attr("class", "tori_text")).next()
+            .map(|n| n.text().lines().map(|l| l.trim()).collect::<Vec<&str>>().join("\n").trim().to_string())
+            .unwrap_or_default();
+        // Listing pictures are hosted under /tori/, unlike the site layout images
+        let image_urls: Vec<String> = page.find(Name("img"))
+            .filter_map(|n| n.attr("src"))
+            .filter(|src| src.contains("/tori/"))
+            .map(|src| if src.starts_with('/') { format!("{}{src}", self.base_url) } else { src.to_string() })
+            .collect();
+
+        let text = page.find(Name("body")).next().map(|n| n.text()).unwrap_or_default();
+        let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+        Ok(MuusListingDetail {
+            url: url.to_string(),
+            title,
+            description,
+            image_urls,
+            seller: field_value(&text, "Ilmoittaja:"),
+            contact: field_value(&text, "Yhteydenotto:"),
+            dtime_add: parse_dtime(&RE_ADD, &text),
+            dtime_upd: parse_dtime(&RE_UPD, &text),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testutil::{fixture, serve_with};
+
+    fn parse_fixture(name: &str) -> MuusResults {
+        page_to_listings(Document::from(fixture(name).as_str()), "https://muusikoiden.net")
+    }
+
+    /// Serves the fixture of the first route whose pattern occurs in the request target, 404 otherwise.
+    fn serve(routes: Vec<(&'static str, &'static str)>) -> String {
+        serve_with(move |target, _| match routes.iter().find(|(pattern, _)| target.contains(pattern)) {
+            Some((_, name)) => (200, fixture(name)),
+            None => (404, String::new()),
+        })
+    }
+
+    /// Client without the delays meant for the real site.
+    fn client(base_url: &str) -> MuusClient {
+        let settings = MuusClientSettings {
+            requests_per_sec: 1000.0,
+            backoff: Duration::from_millis(10),
+            ..Config::default().muus_settings()
+        };
+        MuusClient::new(base_url, settings)
+    }
+
+    fn search(text: &str) -> MuusSearch {
+        MuusSearch {
+            user_id: "tester".to_string(),
+            chat_id: 1,
+            uniq_id: 0,
+            text: text.to_string(),
+            dtime_req: Utc::now(),
+            dtime_prev_req: None,
+            seen_ids: HashSet::new(),
+            filters: MuusFilters::default(),
+            imported: false,
+        }
+    }
+
+    #[test]
+    fn single_page() {
+        let results = parse_fixture("single_page.html");
+        assert_eq!(results.rows, 3);
+        assert!(results.warnings.is_empty());
+
+        let listing = &results.listings[0];
+        assert_eq!(listing.id, 3401240);
+        assert_eq!(listing.title, "Elektron Digitakt");
+        assert_eq!(listing.url, "https://muusikoiden.net/tori/ilmoitus/3401240");
+        assert_eq!(listing.kind, Some(MuusListingKind::Selling));
+        assert_eq!(listing.price, Some(550));
+        assert_eq!(listing.region.as_deref(), Some("Helsinki"));
+        assert_eq!(listing.seller.as_deref(), Some("synthguy"));
+        // Finnish winter time is UTC+2
+        assert_eq!(listing.dtime_add, Utc.with_ymd_and_hms(2024, 3, 14, 16, 22, 0).unwrap());
+        assert_eq!(listing.dtime_upd, Utc.with_ymd_and_hms(2024, 3, 15, 7, 10, 0).unwrap());
+
+        // Thousands are separated with a space
+        assert_eq!(results.listings[2].price, Some(1100));
+    }
+
+    #[test]
+    fn no_results() {
+        let results = parse_fixture("no_results.html");
+        assert_eq!(results.rows, 0);
+        assert!(results.listings.is_empty());
+        assert!(results.warnings.is_empty());
+    }
+
+    #[test]
+    fn no_modified_date() {
+        let results = parse_fixture("no_modified.html");
+        assert_eq!(results.listings.len(), 2);
+        for listing in results.listings.iter() {
+            assert_eq!(listing.dtime_upd, listing.dtime_add);
+        }
+        assert_eq!(results.listings[0].dtime_add, Utc.with_ymd_and_hms(2024, 3, 21, 7, 45, 0).unwrap());
+    }
+
+    #[test]
+    fn helsinki_time() {
+        let dtime = |text: &str| parse_dtime(&RE_ADD, text).unwrap();
+        // Summer time is UTC+3
+        assert_eq!(dtime("Lisätty: 15.07.2024 12:00"), Utc.with_ymd_and_hms(2024, 7, 15, 9, 0, 0).unwrap());
+        // 03:30 happens twice on 27.10.2024, the later one is in winter time
+        assert_eq!(dtime("Lisätty: 27.10.2024 03:30"), Utc.with_ymd_and_hms(2024, 10, 27, 1, 30, 0).unwrap());
+        // 03:30 does not exist on 31.03.2024, the clocks go from 03:00 to 04:00
+        assert_eq!(dtime("Lisätty: 31.03.2024 03:30"), Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn html_entities() {
+        let results = parse_fixture("entities.html");
+        assert_eq!(results.listings[0].title, "Boss DS-1 & SD-1 \"vintage\" pedaalit");
+        assert_eq!(results.listings[0].seller.as_deref(), Some("pedal&co"));
+        assert_eq!(results.listings[1].title, "Roland Juno-60 <huollettu> \u{2013} hieno");
+        assert_eq!(results.listings[1].price, Some(2400));
+    }
+
+    #[test]
+    fn buying_and_selling() {
+        let results = parse_fixture("buy_sell.html");
+        let kinds: Vec<Option<MuusListingKind>> = results.listings.iter().map(|l| l.kind).collect();
+        assert_eq!(kinds, vec![Some(MuusListingKind::Buying), Some(MuusListingKind::Selling),
+                               Some(MuusListingKind::Selling)]);
+        // Listings without a price
+        assert_eq!(results.listings[2].price, None);
+    }
+
+    #[test]
+    fn malformed_rows_are_skipped() {
+        let page = fixture("single_page.html").replacen(r#"<a href="/tori/ilmoitus/3401112">"#, "<a>", 1);
+        let results = page_to_listings(Document::from(page.as_str()), "https://muusikoiden.net");
+        assert_eq!(results.rows, 3);
+        assert_eq!(results.listings.len(), 2);
+        assert_eq!(results.warnings.len(), 1);
+        match &results.warnings[0] {
+            MuusError::Parse { reason, row } => {
+                assert_eq!(reason, "title link");
+                assert!(row.contains("Elektron Model:Cycles"));
+            }
+            err => panic!("unexpected warning {err}"),
+        }
+    }
+
+    #[test]
+    fn search_single_page() {
+        let base_url = serve(vec![("keyword=elektron", "single_page.html")]);
+        let client = client(&base_url);
+        let results = client.search_muus_market(search("elektron")).unwrap();
+
+        // Listings are returned in chronological order, with the links to the same server
+        let ids: Vec<u64> = results.listings.iter().map(|l| l.id).collect();
+        assert_eq!(ids, vec![3400987, 3401112, 3401240]);
+        assert_eq!(results.listings[0].url, format!("{base_url}/tori/ilmoitus/3400987"));
+    }
+
+    #[test]
+    fn search_multiple_pages() {
+        let client = client(&serve_kitara());
+        let results = client.search_muus_market(search("kitara")).unwrap();
+
+        assert_eq!(results.rows, 5);
+        let ids: Vec<u64> = results.listings.iter().map(|l| l.id).collect();
+        assert_eq!(ids, vec![3401870, 3401901, 3401950, 3402001, 3402005]);
+    }
+
+    #[test]
+    fn search_no_results() {
+        let base_url = serve(vec![("keyword=xyzzy", "no_results.html")]);
+        let client = client(&base_url);
+        let results = client.search_muus_market(search("xyzzy")).unwrap();
+        assert!(results.listings.is_empty());
+    }
+
+    #[test]
+    fn search_http_error() {
+        let base_url = serve(vec![]);
+        let client = client(&base_url);
+        match client.search_muus_market(search("elektron")) {
+            Err(MuusError::HttpStatus { status, .. }) => assert_eq!(status, 404),
+            Err(err) => panic!("unexpected error {err}"),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn listing_detail() {
+        let base_url = serve(vec![("/tori/ilmoitus/3401240", "listing_detail.html")]);
+        let client = client(&base_url);
+        let url = format!("{base_url}/tori/ilmoitus/3401240");
+        let detail = client.fetch_listing_detail(&url).unwrap();
+
+        assert_eq!(detail.url, url);
+        assert_eq!(detail.title, "Elektron Digitakt");
+        assert_eq!(detail.description, "Hyväkuntoinen, alkuperäinen laatikko mukana.\n\
+                                        Päivitetty uusimpaan käyttöjärjestelmään.\n\nPostitus onnistuu.");
+        // Only the pictures of the listing, with relative links to the same server
+        assert_eq!(detail.image_urls, vec![format!("{base_url}/tori/kuvat/3401240_1_pieni.jpg"),
+                                           "https://muusikoiden.net/tori/kuvat/3401240_2_pieni.jpg".to_string()]);
+        assert_eq!(detail.seller.as_deref(), Some("synthguy"));
+        assert_eq!(detail.contact.as_deref(), Some("sähköposti tai puhelin"));
+        // Winter time, UTC+2
+        assert_eq!(detail.dtime_add, Some(Utc.with_ymd_and_hms(2024, 3, 14, 16, 22, 0).unwrap()));
+        assert_eq!(detail.dtime_upd, Some(Utc.with_ymd_and_hms(2024, 3, 15, 7, 10, 0).unwrap()));
+    }
+
+    /// The fixtures only imitate the site, so check the parsers against it too: `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs the live site"]
+    fn live_site() {
+        let settings = MuusClientSettings { max_pages: 1, ..Config::default().muus_settings() };
+        let client = MuusClient::new("https://muusikoiden.net", settings);
+        let results = client.search_muus_market(search("kitara")).unwrap();
+        assert!(results.warnings.is_empty(), "{:?}", results.warnings);
+        assert_eq!(results.listings.len(), results.rows);
+        // Not every listing has all the fields, but a page of them does
+        assert!(results.listings.iter().any(|l| l.kind.is_some() && l.price.is_some()));
+        assert!(results.listings.iter().any(|l| l.region.is_some() && l.seller.is_some()));
+        assert!(results.listings.iter().any(|l| l.dtime_upd > l.dtime_add));
+
+        let listing = results.listings.last().unwrap();
+        let detail = client.fetch_listing_detail(&listing.url).unwrap();
+        assert_eq!(detail.title, listing.title);
+        assert!(!detail.description.is_empty());
+        assert_eq!(detail.seller, listing.seller);
+        assert_eq!(detail.dtime_add, Some(listing.dtime_add));
+    }
+
+    #[test]
+    fn search_retries_server_errors() {
+        // The first two requests fail
+        let base_url = serve_with(|_, n| if n < 2 { (503, String::new()) } else { (200, fixture("single_page.html")) });
+        let client = client(&base_url);
+        let results = client.search_muus_market(search("elektron")).unwrap();
+        assert_eq!(results.listings.len(), 3);
+    }
+
+    #[test]
+    fn search_gives_up_after_retries() {
+        let base_url = serve_with(|_, _| (429, String::new()));
+        let client = client(&base_url);
+        match client.search_muus_market(search("elektron")) {
+            Err(MuusError::HttpStatus { status, .. }) => assert_eq!(status, 429),
+            Err(err) => panic!("unexpected error {err}"),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn retry_delays() {
+        let settings = MuusClientSettings {
+            backoff: Duration::from_secs(5),
+            max_retries: 3,
+            ..Config::default().muus_settings()
+        };
+        let client = MuusClient::new("http://127.0.0.1", settings);
+        let delays: Vec<u64> = (0..3).map(|n| client.retry_delay(n, None).as_secs()).collect();
+        assert_eq!(delays, vec![5, 10, 20]);
+        assert_eq!(client.retry_delay(0, Some(Duration::from_secs(7))), Duration::from_secs(7));
+        // Capped at the backoff after the last attempt
+        assert_eq!(client.retry_delay(0, Some(Duration::from_secs(86400))), Duration::from_secs(40));
+
+        let settings = MuusClientSettings { max_retries: 40, ..client.settings };
+        let client = MuusClient::new("http://127.0.0.1", settings);
+        assert!(client.retry_delay(35, None) > Duration::from_secs(86400));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let base_url = serve(vec![("keyword=elektron", "single_page.html")]);
+        let settings = MuusClientSettings { requests_per_sec: 20.0, ..Config::default().muus_settings() };
+        let client = MuusClient::new(&base_url, settings);
+        let dtime_start = Instant::now();
+        for _ in 0..3 {
+            client.search_muus_market(search("elektron")).unwrap();
+        }
+        // The first request is sent immediately, the next ones 50 ms apart
+        assert!(dtime_start.elapsed() >= Duration::from_millis(100));
+    }
+
+    fn serve_kitara() -> String {
+        serve(vec![
+            ("offset=4", "multi_page_3.html"),
+            ("offset=2", "multi_page_2.html"),
+            ("keyword=kitara", "multi_page_1.html"),
+        ])
+    }
+
+    #[test]
+    fn search_stops_at_cutoff() {
+        let client = client(&serve_kitara());
+        // Everything on the second page is older than the previous run
+        let search = MuusSearch {
+            dtime_prev_req: Some(Utc.with_ymd_and_hms(2024, 3, 19, 7, 0, 0).unwrap()),
+            ..search("kitara")
         };
+        let results = client.search_muus_market(search).unwrap();
+        assert_eq!(results.rows, 4);
     }
 
-    listings = listings.into_iter().rev().collect();  // Sort in chronological order
-    // println!("Listings found: {}", listings.iter().count());
-    return Ok(listings)
+    #[test]
+    fn search_stops_at_seen() {
+        let client = client(&serve_kitara());
+        let search = MuusSearch { seen_ids: HashSet::from([3402005, 3402001]), ..search("kitara") };
+        let results = client.search_muus_market(search).unwrap();
+        assert_eq!(results.rows, 2);
+    }
+
+    #[test]
+    fn search_stops_at_page_limit() {
+        let base_url = serve_kitara();
+        let settings = MuusClientSettings { max_pages: 2, ..client(&base_url).settings };
+        let client = MuusClient::new(&base_url, settings);
+        let results = client.search_muus_market(search("kitara")).unwrap();
+        assert_eq!(results.rows, 4);
+    }
+
+    #[test]
+    fn search_with_filters() {
+        let base_url = serve(vec![("keyword=moog&price_max=1500&type=myydaan", "buy_sell.html")]);
+        let client = client(&base_url);
+        let filters = MuusFilters {
+            kind: Some(MuusListingKind::Selling),
+            price_max: Some(1500),
+            ..MuusFilters::default()
+        };
+        let results = client.search_muus_market(MuusSearch { filters, ..search("moog") }).unwrap();
+        assert_eq!(results.rows, 3);
+    }
+
+    #[test]
+    fn grouped_searches() {
+        let dtime = |h| Utc.with_ymd_and_hms(2024, 3, 19, h, 0, 0).unwrap();
+        let moog = MuusSearch { dtime_prev_req: Some(dtime(7)), seen_ids: HashSet::from([1, 2, 3]), ..search("moog+sub") };
+        let searches = vec![
+            moog.clone(),
+            MuusSearch { chat_id: 2, filters: MuusFilters { price_max: Some(500), ..MuusFilters::default() }, ..moog.clone() },
+            MuusSearch { chat_id: 3, text: "kitara".to_string(), ..moog.clone() },
+            MuusSearch { chat_id: 4, text: "Moog++Sub".to_string(), dtime_prev_req: Some(dtime(5)),
+                         seen_ids: HashSet::from([2, 3, 4]), ..moog.clone() },
+            MuusSearch { chat_id: 5, seen_ids: HashSet::from([3]), ..moog.clone() },
+        ];
+        let groups = group_searches(searches);
+        let members: Vec<Vec<i64>> = groups.iter().map(|g| g.members.iter().map(|m| m.chat_id).collect()).collect();
+        assert_eq!(members, vec![vec![1, 4, 5], vec![2], vec![3]]);
+
+        // Members keep their own state
+        assert_eq!(groups[0].members[1].seen_ids, HashSet::from([2, 3, 4]));
+        assert_eq!(groups[0].search.text, "moog+sub");
+        assert_eq!(groups[0].search.seen_ids, HashSet::from([3]));
+        assert_eq!(groups[0].search.dtime_prev_req, Some(dtime(5)));
+        assert_eq!(groups[1].search.seen_ids, moog.seen_ids);
+
+        // A member on its first run needs all the pages
+        let groups = group_searches(vec![moog.clone(), MuusSearch { dtime_prev_req: None, ..moog }]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].search.dtime_prev_req, None);
+    }
+
+    #[test]
+    fn local_filter() {
+        let listings = parse_fixture("single_page.html").listings;
+        let titles = |filter: &MuusLocalFilter| {
+            let title_re = filter.title_re();
+            listings.iter()
+                .filter(|l| filter.matches(l, title_re.as_ref()))
+                .map(|l| l.title.as_str())
+                .collect::<Vec<&str>>()
+        };
+
+        let filter = MuusLocalFilter { require: vec!["ELEKTRON".to_string()], ..MuusLocalFilter::default() };
+        assert_eq!(titles(&filter).len(), 3);
+        let filter = MuusLocalFilter { exclude: vec!["rytm".to_string()], ..MuusLocalFilter::default() };
+        assert_eq!(titles(&filter), vec!["Elektron Digitakt", "Elektron Model:Cycles"]);
+        let filter = MuusLocalFilter { title_regex: Some(r"mk\s?ii$".to_string()), ..MuusLocalFilter::default() };
+        assert_eq!(titles(&filter), vec!["Elektron Analog Rytm MKII"]);
+        let filter = MuusLocalFilter { title_regex: Some("(".to_string()), ..MuusLocalFilter::default() };
+        assert_eq!(titles(&filter).len(), 3);
+        let filter = MuusLocalFilter { price_min: Some(250), price_max: Some(1000), ..MuusLocalFilter::default() };
+        assert_eq!(titles(&filter), vec!["Elektron Digitakt"]);
+    }
 }
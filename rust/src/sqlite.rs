@@ -0,0 +1,254 @@
+use std::path::Path;
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use crate::store::{DBNotification, DBQuery, DBSeenListing, DBUser, Store, StoreError, DEFAULT_TIMEZONE,
+                   NOTIFICATIONS_MAX};
+
+
+const SCHEMA_VERSION: i64 = 2;  // kept in `PRAGMA user_version`, the tables below are version 2
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        chat_id INTEGER PRIMARY KEY,
+        user_id TEXT NOT NULL,
+        timezone TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS queries (
+        chat_id INTEGER NOT NULL REFERENCES users (chat_id) ON DELETE CASCADE,
+        uniq_id INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        dtime_prev_req TEXT,
+        notify_edits INTEGER NOT NULL,
+        filters TEXT NOT NULL,
+        local_filter TEXT NOT NULL,
+        paused INTEGER NOT NULL,
+        interval INTEGER,
+        imported INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (chat_id, uniq_id)
+    );
+    CREATE TABLE IF NOT EXISTS seen_listings (
+        chat_id INTEGER NOT NULL,
+        uniq_id INTEGER NOT NULL,
+        listing_id INTEGER NOT NULL,
+        dtime_seen TEXT NOT NULL,
+        title TEXT NOT NULL,
+        price INTEGER,
+        text_hash INTEGER,
+        PRIMARY KEY (chat_id, uniq_id, listing_id),
+        FOREIGN KEY (chat_id, uniq_id) REFERENCES queries (chat_id, uniq_id) ON DELETE CASCADE
+    );
+    CREATE TABLE IF NOT EXISTS notifications (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        chat_id INTEGER NOT NULL REFERENCES users (chat_id) ON DELETE CASCADE,
+        uniq_id INTEGER NOT NULL,
+        listing_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        url TEXT NOT NULL,
+        edited INTEGER NOT NULL,
+        dtime_sent TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS notifications_chat_id ON notifications (chat_id, id);
+";
+
+/// Statements upgrading the schema from version `i + 1` to `i + 2`.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE queries ADD COLUMN imported INTEGER NOT NULL DEFAULT 0;",
+];
+
+/// Keeps the state in an SQLite database, writing only the rows of the changed user or query.
+/// A run still rewrites all the seen listings of its query, at most `SEEN_LISTINGS_MAX` rows.
+///
+/// The IDs and hashes are stored as `i64` bit patterns, since SQLite integers are signed.
+/// The filters of a query are stored as JSON.
+pub(crate) struct SqliteStore {
+    conn: Connection,
+}
+
+fn to_sql_err(err: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(err))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(idx)?;
+    serde_json::from_str(&text)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(err)))
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<SqliteStore, StoreError> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(StoreError::Schema(format!("version {version} is newer than the supported {SCHEMA_VERSION}")));
+        }
+        let tx = conn.transaction()?;
+        if version == 0 {
+            tx.execute_batch(SCHEMA)?;
+        } else {
+            for migration in &MIGRATIONS[version as usize - 1..] {
+                tx.execute_batch(migration)?;
+            }
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
+        Ok(SqliteStore { conn })
+    }
+
+    fn user_exists(&self, chat_id: i64) -> Result<bool, StoreError> {
+        Ok(self.conn.query_row("SELECT 1 FROM users WHERE chat_id = ?1", [chat_id], |_| Ok(()))
+            .optional()?.is_some())
+    }
+
+    fn queries(&self, chat_id: i64) -> Result<Vec<DBQuery>, StoreError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT uniq_id, text, dtime_prev_req, notify_edits, filters, local_filter, paused, interval, imported
+             FROM queries WHERE chat_id = ?1 ORDER BY rowid")?;
+        let mut queries = stmt.query_map([chat_id], |row| Ok(DBQuery {
+            uniq_id: row.get::<_, i64>(0)? as u64,
+            text: row.get(1)?,
+            dtime_prev_req: row.get(2)?,
+            seen: Vec::new(),
+            notify_edits: row.get(3)?,
+            filters: from_json(row, 4)?,
+            local_filter: from_json(row, 5)?,
+            paused: row.get(6)?,
+            interval: row.get::<_, Option<i64>>(7)?.map(|i| i as u64),
+            imported: row.get(8)?,
+        }))?.collect::<Result<Vec<DBQuery>, _>>()?;
+
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT listing_id, dtime_seen, title, price, text_hash
+             FROM seen_listings WHERE chat_id = ?1 AND uniq_id = ?2 ORDER BY rowid")?;
+        for q in queries.iter_mut() {
+            q.seen = stmt.query_map(params![chat_id, q.uniq_id as i64], |row| Ok(DBSeenListing {
+                id: row.get::<_, i64>(0)? as u64,
+                dtime_seen: row.get(1)?,
+                title: row.get(2)?,
+                price: row.get(3)?,
+                text_hash: row.get::<_, Option<i64>>(4)?.map(|h| h as u64),
+            }))?.collect::<Result<Vec<DBSeenListing>, _>>()?;
+        }
+        Ok(queries)
+    }
+
+    fn user_from_row(&self, row: (i64, String, String)) -> Result<DBUser, StoreError> {
+        let (chat_id, user_id, timezone) = row;
+        Ok(DBUser {
+            chat_id,
+            user_id,
+            queries: self.queries(chat_id)?,
+            timezone: timezone.parse::<Tz>().unwrap_or(DEFAULT_TIMEZONE),
+        })
+    }
+}
+
+impl Store for SqliteStore {
+    fn users(&self) -> Result<Vec<DBUser>, StoreError> {
+        let mut stmt = self.conn.prepare_cached("SELECT chat_id, user_id, timezone FROM users ORDER BY rowid")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter().map(|row| self.user_from_row(row)).collect()
+    }
+
+    fn user(&self, chat_id: i64) -> Result<Option<DBUser>, StoreError> {
+        let row = self.conn.query_row(
+            "SELECT chat_id, user_id, timezone FROM users WHERE chat_id = ?1", [chat_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).optional()?;
+        row.map(|row| self.user_from_row(row)).transpose()
+    }
+
+    fn put_user(&mut self, user: &DBUser) -> Result<(), StoreError> {
+        self.conn.execute(
+            "INSERT INTO users (chat_id, user_id, timezone) VALUES (?1, ?2, ?3)
+             ON CONFLICT (chat_id) DO UPDATE SET user_id = excluded.user_id, timezone = excluded.timezone",
+            params![user.chat_id, user.user_id, user.timezone.name()])?;
+        Ok(())
+    }
+
+    fn remove_user(&mut self, chat_id: i64) -> Result<bool, StoreError> {
+        Ok(self.conn.execute("DELETE FROM users WHERE chat_id = ?1", [chat_id])? > 0)
+    }
+
+    fn put_query(&mut self, chat_id: i64, query: &DBQuery) -> Result<(), StoreError> {
+        if !self.user_exists(chat_id)? {
+            return Err(StoreError::UnknownUser(chat_id));
+        }
+        self.conn.execute(
+            "INSERT INTO queries (chat_id, uniq_id, text, dtime_prev_req, notify_edits, filters, local_filter,
+                                  paused, interval, imported)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT (chat_id, uniq_id) DO UPDATE SET
+                 text = excluded.text, dtime_prev_req = excluded.dtime_prev_req,
+                 notify_edits = excluded.notify_edits, filters = excluded.filters,
+                 local_filter = excluded.local_filter, paused = excluded.paused, interval = excluded.interval,
+                 imported = excluded.imported",
+            params![chat_id, query.uniq_id as i64, query.text, query.dtime_prev_req, query.notify_edits,
+                    serde_json::to_string(&query.filters).map_err(to_sql_err)?,
+                    serde_json::to_string(&query.local_filter).map_err(to_sql_err)?,
+                    query.paused, query.interval.map(|i| i as i64), query.imported])?;
+        Ok(())
+    }
+
+    fn remove_query(&mut self, chat_id: i64, uniq_id: u64) -> Result<bool, StoreError> {
+        Ok(self.conn.execute("DELETE FROM queries WHERE chat_id = ?1 AND uniq_id = ?2",
+                             params![chat_id, uniq_id as i64])? > 0)
+    }
+
+    fn save_run(&mut self, chat_id: i64, uniq_id: u64, dtime_req: DateTime<Utc>,
+                seen: &[DBSeenListing]) -> Result<bool, StoreError> {
+        let tx = self.conn.transaction()?;
+        let updated = tx.execute("UPDATE queries SET dtime_prev_req = ?3, imported = 0 WHERE chat_id = ?1 AND uniq_id = ?2",
+                                 params![chat_id, uniq_id as i64, dtime_req])?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM seen_listings WHERE chat_id = ?1 AND uniq_id = ?2", params![chat_id, uniq_id as i64])?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO seen_listings (chat_id, uniq_id, listing_id, dtime_seen, title, price, text_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+            for s in seen {
+                stmt.execute(params![chat_id, uniq_id as i64, s.id as i64, s.dtime_seen, s.title, s.price,
+                                     s.text_hash.map(|h| h as i64)])?;
+            }
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn log_notification(&mut self, notification: &DBNotification) -> Result<(), StoreError> {
+        if !self.user_exists(notification.chat_id)? {
+            return Err(StoreError::UnknownUser(notification.chat_id));
+        }
+        let n = notification;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO notifications (chat_id, uniq_id, listing_id, title, url, edited, dtime_sent)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![n.chat_id, n.uniq_id as i64, n.listing_id as i64, n.title, n.url, n.edited, n.dtime_sent])?;
+        tx.execute(
+            "DELETE FROM notifications WHERE chat_id = ?1 AND id NOT IN
+                 (SELECT id FROM notifications WHERE chat_id = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![n.chat_id, NOTIFICATIONS_MAX as i64])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn notifications(&self, chat_id: i64, limit: usize) -> Result<Vec<DBNotification>, StoreError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT chat_id, uniq_id, listing_id, title, url, edited, dtime_sent
+             FROM notifications WHERE chat_id = ?1 ORDER BY id DESC LIMIT ?2")?;
+        let notifications = stmt.query_map(params![chat_id, limit as i64], |row| Ok(DBNotification {
+            chat_id: row.get(0)?,
+            uniq_id: row.get::<_, i64>(1)? as u64,
+            listing_id: row.get::<_, i64>(2)? as u64,
+            title: row.get(3)?,
+            url: row.get(4)?,
+            edited: row.get(5)?,
+            dtime_sent: row.get(6)?,
+        }))?.collect::<Result<Vec<DBNotification>, _>>()?;
+        Ok(notifications)
+    }
+}
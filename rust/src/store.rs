@@ -0,0 +1,699 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config::{Config, StoreKind};
+use crate::db::{self, DbError};
+use crate::muus::{MuusFilters, MuusListing, MuusLocalFilter};
+use crate::sqlite::SqliteStore;
+
+
+pub(crate) const DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Helsinki;  // of the times shown to the users
+pub(crate) const NOTIFICATIONS_MAX: usize = 100;  // per chat, how many sent notifications to log
+const BACKUP_INTERVAL: Duration = Duration::from_secs(3600);  // how often to rotate the JSON db backups at most
+const SEEN_LISTINGS_MAX: usize = 1000;  // per query, how many sent listing IDs to remember
+const SEEN_LISTINGS_TTL: i64 = 90;  // in days, how long to remember a listing no longer in the results
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct DBUser {
+    pub chat_id: i64,
+    pub user_id: String,
+    pub queries: Vec<DBQuery>,
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+}
+
+fn default_timezone() -> Tz { DEFAULT_TIMEZONE }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct DBQuery {
+    pub uniq_id: u64,
+    pub text: String,
+    pub dtime_prev_req: Option<DateTime<Utc>>,  // None until the first run sets the baseline
+    #[serde(default)]
+    pub seen: Vec<DBSeenListing>,
+    #[serde(default = "default_true")]
+    pub notify_edits: bool,  // whether to report the edits of the already sent listings
+    #[serde(default)]
+    pub filters: MuusFilters,
+    #[serde(default)]
+    pub local_filter: MuusLocalFilter,
+    #[serde(default)]
+    pub paused: bool,  // skipped by the search, keeping the seen listings
+    #[serde(default)]
+    pub interval: Option<u64>,  // in seconds, None for the configured cooldown interval
+    #[serde(default)]
+    pub imported: bool,  // from the Python bot or a db without seen listings, until its first run here
+}
+
+fn default_true() -> bool { true }
+
+impl DBQuery {
+    /// A query that has not been run yet, with the default settings.
+    pub fn new(uniq_id: u64, text: &str) -> DBQuery {
+        DBQuery {
+            uniq_id,
+            text: text.to_string(),
+            dtime_prev_req: None,
+            seen: Vec::new(),
+            notify_edits: true,
+            filters: MuusFilters::default(),
+            local_filter: MuusLocalFilter::default(),
+            paused: false,
+            interval: None,
+            imported: false,
+        }
+    }
+
+    /// Period between the runs of the query, in seconds.
+    ///
+    /// A custom one is kept within the configured limits, which may have changed since it was set.
+    pub fn interval(&self, config: &Config) -> u64 {
+        self.interval.map_or(config.cooldown_interval, |i| i.clamp(config.interval_min, config.interval_max))
+    }
+
+    /// Marks the listings as seen at `dtime` and returns the ones that are new or edited since last seen.
+    pub fn take_news(&mut self, listings: Vec<MuusListing>, dtime: DateTime<Utc>) -> Vec<ListingNews> {
+        let mut news: Vec<ListingNews> = Vec::new();
+        for listing in listings {
+            match self.seen.iter_mut().find(|s| s.id == listing.id) {
+                Some(s) => {
+                    // Entries from before the state was recorded only get their state filled in
+                    let is_edited = s.text_hash.is_some_and(|h| h != listing.text_hash);
+                    let title_prev = std::mem::replace(&mut s.title, listing.title.clone());
+                    let price_prev = std::mem::replace(&mut s.price, listing.price);
+                    s.text_hash = Some(listing.text_hash);
+                    s.dtime_seen = dtime;
+                    if is_edited {
+                        news.push(ListingNews::Edited { listing, title_prev, price_prev });
+                    }
+                }
+                None => {
+                    self.seen.push(DBSeenListing {
+                        id: listing.id,
+                        dtime_seen: dtime,
+                        title: listing.title.clone(),
+                        price: listing.price,
+                        text_hash: Some(listing.text_hash),
+                    });
+                    news.push(ListingNews::New(listing));
+                }
+            }
+        }
+        self.prune_seen(dtime);
+        news
+    }
+
+    /// Forgets the stale listings and keeps at most `SEEN_LISTINGS_MAX` most recently seen ones.
+    pub fn prune_seen(&mut self, dtime_now: DateTime<Utc>) {
+        self.seen.retain(|s| (dtime_now - s.dtime_seen).num_days() < SEEN_LISTINGS_TTL);
+        if self.seen.len() > SEEN_LISTINGS_MAX {
+            self.seen.sort_by_key(|s| s.dtime_seen);
+            self.seen.drain(..self.seen.len() - SEEN_LISTINGS_MAX);
+        }
+    }
+}
+
+/// What a run of a query found out about a listing.
+pub(crate) enum ListingNews {
+    New(MuusListing),
+    Edited { listing: MuusListing, title_prev: String, price_prev: Option<u32> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct DBSeenListing {
+    pub id: u64,
+    pub dtime_seen: DateTime<Utc>,  // last time the listing was returned by the query
+    // State of the listing when last seen
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub price: Option<u32>,
+    #[serde(default)]
+    pub text_hash: Option<u64>,
+}
+
+/// A listing sent to a chat.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct DBNotification {
+    pub chat_id: i64,
+    pub uniq_id: u64,  // of the query that found the listing
+    pub listing_id: u64,
+    pub title: String,
+    pub url: String,
+    pub edited: bool,  // whether it was an edit of an already sent listing
+    pub dtime_sent: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub(crate) enum StoreError {
+    Json(DbError),
+    Sqlite(rusqlite::Error),
+    /// The chat has no account.
+    UnknownUser(i64),
+    /// The db has a schema this version cannot read.
+    Schema(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Json(err) => write!(f, "{err}"),
+            StoreError::Sqlite(err) => write!(f, "sqlite: {err}"),
+            StoreError::UnknownUser(chat_id) => write!(f, "no user with chat ID {chat_id}"),
+            StoreError::Schema(reason) => write!(f, "unsupported db schema: {reason}"),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StoreError::Json(err) => Some(err),
+            StoreError::Sqlite(err) => Some(err),
+            StoreError::UnknownUser(_) | StoreError::Schema(_) => None,
+        }
+    }
+}
+
+impl From<DbError> for StoreError {
+    fn from(err: DbError) -> Self { StoreError::Json(err) }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self { StoreError::Sqlite(err) }
+}
+
+/// Persistent state of the bot: users, their queries, the listings seen by the queries
+/// and the log of sent notifications.
+pub(crate) trait Store: Send {
+    /// All users with their queries.
+    fn users(&self) -> Result<Vec<DBUser>, StoreError>;
+    fn user(&self, chat_id: i64) -> Result<Option<DBUser>, StoreError>;
+    /// Adds the user or updates its settings, leaving the queries as they are.
+    fn put_user(&mut self, user: &DBUser) -> Result<(), StoreError>;
+    /// Removes the user with everything recorded for it, false if there was none.
+    fn remove_user(&mut self, chat_id: i64) -> Result<bool, StoreError>;
+    /// Adds the query or updates its settings, leaving the seen listings as they are.
+    fn put_query(&mut self, chat_id: i64, query: &DBQuery) -> Result<(), StoreError>;
+    /// Removes the query with its seen listings, false if there was none.
+    fn remove_query(&mut self, chat_id: i64, uniq_id: u64) -> Result<bool, StoreError>;
+    /// Records a run of the query: its time and all the listings seen so far, false if there is no such query.
+    /// The query is no longer `imported` after it.
+    fn save_run(&mut self, chat_id: i64, uniq_id: u64, dtime_req: DateTime<Utc>,
+                seen: &[DBSeenListing]) -> Result<bool, StoreError>;
+    /// Logs a sent notification, keeping the latest `NOTIFICATIONS_MAX` per chat.
+    fn log_notification(&mut self, notification: &DBNotification) -> Result<(), StoreError>;
+    /// The latest notifications sent to the chat, newest first.
+    fn notifications(&self, chat_id: i64, limit: usize) -> Result<Vec<DBNotification>, StoreError>;
+}
+
+/// Opens the store selected in the config.
+pub(crate) fn open_store(config: &Config) -> Result<Box<dyn Store>, StoreError> {
+    Ok(match config.store {
+        StoreKind::Json => Box::new(JsonStore::open(&config.db_path, config.db_backups)?),
+        StoreKind::Sqlite => Box::new(SqliteStore::open(&config.db_path)?),
+    })
+}
+
+/// Current version of the JSON file layout, see [`MIGRATIONS`].
+const SCHEMA_VERSION: u64 = 1;
+
+/// Upgrades the JSON db from the schema version equal to its index to the next one.
+const MIGRATIONS: [fn(Value) -> Result<Value, String>; SCHEMA_VERSION as usize] = [migrate_v0];
+
+/// Version 0 is the bare array of users, from before the versioning.
+///
+/// Its queries may predate the seen listings, so those already run are marked `imported`:
+/// their first run then reports only the listings modified since, instead of every listing found.
+fn migrate_v0(mut db: Value) -> Result<Value, String> {
+    let queries = db.as_array_mut().into_iter().flatten()
+        .filter_map(|user| user.get_mut("queries").and_then(Value::as_array_mut))
+        .flatten()
+        .filter_map(Value::as_object_mut);
+    for q in queries {
+        if !q.contains_key("seen") && q.get("dtime_prev_req").is_some_and(|d| !d.is_null()) {
+            q.insert("imported".to_string(), Value::Bool(true));
+        }
+    }
+    Ok(json!({ "schema_version": 1, "users": db }))
+}
+
+fn schema_version(db: &Value) -> Result<u64, String> {
+    match db {
+        Value::Array(_) => Ok(0),
+        Value::Object(map) => map.get("schema_version").and_then(Value::as_u64)
+            .ok_or_else(|| "no schema_version".to_string()),
+        _ => Err("not an array or an object".to_string()),
+    }
+}
+
+/// Brings the db to [`SCHEMA_VERSION`], returns it with the version it had.
+fn migrate(mut db: Value) -> Result<(Value, u64), String> {
+    let version = schema_version(&db)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!("version {version} is newer than the supported {SCHEMA_VERSION}"));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        db = migration(db).map_err(|err| format!("migration from version {from}: {err}"))?;
+        let to = schema_version(&db)?;
+        if to != from as u64 + 1 {
+            return Err(format!("migration from version {from} led to version {to}"));
+        }
+    }
+    Ok((db, version))
+}
+
+/// Layout of the JSON file, `U` is owned for reading and borrowed for writing.
+#[derive(Serialize, Deserialize)]
+struct JsonRoot<U> {
+    schema_version: u64,
+    users: U,
+}
+
+/// User as written to the JSON file, with its notification log.
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonUser {
+    #[serde(flatten)]
+    user: DBUser,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notifications: Vec<DBNotification>,
+}
+
+/// Keeps everything in memory and rewrites the whole JSON file on every change.
+///
+/// The backups are rotated on the first change and then at most every `BACKUP_INTERVAL`,
+/// so that they keep states far enough apart to fall back on, not the last few writes.
+pub(crate) struct JsonStore {
+    path: PathBuf,
+    backups: usize,
+    users: Vec<JsonUser>,
+    dtime_backup: Option<Instant>,  // of the last rotation of the backups
+}
+
+impl JsonStore {
+    /// Opens the db, migrating it to the current schema first.
+    ///
+    /// A migrated db is kept as "{path}.schema-v{N}" before being rewritten in the new schema.
+    pub fn open(path: &Path, backups: usize) -> Result<JsonStore, StoreError> {
+        let Some(db) = db::load::<Value>(path, backups)? else {
+            return Ok(JsonStore { path: path.to_path_buf(), backups, users: Vec::new(), dtime_backup: None });
+        };
+        // The db may have come from a backup, so the original is kept from what was read, not from `path`
+        let db_prev = db.clone();
+        let (db, version) = migrate(db).map_err(StoreError::Schema)?;
+        let root: JsonRoot<Vec<JsonUser>> = serde_json::from_value(db)
+            .map_err(|err| StoreError::Json(DbError::Corrupt { path: path.to_path_buf(), err }))?;
+        let mut store = JsonStore { path: path.to_path_buf(), backups, users: root.users, dtime_backup: None };
+
+        if version < SCHEMA_VERSION {
+            let mut path_backup = path.as_os_str().to_owned();
+            path_backup.push(format!(".schema-v{version}"));
+            db::save(Path::new(&path_backup), &db_prev, 0)?;
+            store.save()?;
+            println!("Migrated the db from schema version {version} to {SCHEMA_VERSION}");
+        }
+        Ok(store)
+    }
+
+    fn save(&mut self) -> Result<(), StoreError> {
+        let root = JsonRoot { schema_version: SCHEMA_VERSION, users: &self.users };
+        let is_backup_due = self.path.exists()
+            && self.dtime_backup.is_none_or(|dtime| dtime.elapsed() >= BACKUP_INTERVAL);
+        db::save(&self.path, &root, if is_backup_due { self.backups } else { 0 })?;
+        if is_backup_due {
+            self.dtime_backup = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn find_mut(&mut self, chat_id: i64) -> Result<&mut JsonUser, StoreError> {
+        self.users.iter_mut().find(|u| u.user.chat_id == chat_id).ok_or(StoreError::UnknownUser(chat_id))
+    }
+}
+
+impl Store for JsonStore {
+    fn users(&self) -> Result<Vec<DBUser>, StoreError> {
+        Ok(self.users.iter().map(|u| u.user.clone()).collect())
+    }
+
+    fn user(&self, chat_id: i64) -> Result<Option<DBUser>, StoreError> {
+        Ok(self.users.iter().find(|u| u.user.chat_id == chat_id).map(|u| u.user.clone()))
+    }
+
+    fn put_user(&mut self, user: &DBUser) -> Result<(), StoreError> {
+        match self.find_mut(user.chat_id) {
+            Ok(u) => {
+                u.user.user_id = user.user_id.clone();
+                u.user.timezone = user.timezone;
+            }
+            Err(_) => {
+                let user = DBUser { queries: Vec::new(), ..user.clone() };
+                self.users.push(JsonUser { user, notifications: Vec::new() });
+            }
+        }
+        self.save()
+    }
+
+    fn remove_user(&mut self, chat_id: i64) -> Result<bool, StoreError> {
+        let len = self.users.len();
+        self.users.retain(|u| u.user.chat_id != chat_id);
+        if self.users.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn put_query(&mut self, chat_id: i64, query: &DBQuery) -> Result<(), StoreError> {
+        let queries = &mut self.find_mut(chat_id)?.user.queries;
+        match queries.iter_mut().find(|q| q.uniq_id == query.uniq_id) {
+            Some(q) => {
+                let seen = std::mem::take(&mut q.seen);
+                *q = DBQuery { seen, ..query.clone() };
+            }
+            None => queries.push(DBQuery { seen: Vec::new(), ..query.clone() }),
+        }
+        self.save()
+    }
+
+    fn remove_query(&mut self, chat_id: i64, uniq_id: u64) -> Result<bool, StoreError> {
+        let queries = &mut self.find_mut(chat_id)?.user.queries;
+        let len = queries.len();
+        queries.retain(|q| q.uniq_id != uniq_id);
+        if queries.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save_run(&mut self, chat_id: i64, uniq_id: u64, dtime_req: DateTime<Utc>,
+                seen: &[DBSeenListing]) -> Result<bool, StoreError> {
+        let Ok(user) = self.find_mut(chat_id) else { return Ok(false) };
+        let Some(q) = user.user.queries.iter_mut().find(|q| q.uniq_id == uniq_id) else { return Ok(false) };
+        q.dtime_prev_req = Some(dtime_req);
+        q.seen = seen.to_vec();
+        q.imported = false;
+        self.save()?;
+        Ok(true)
+    }
+
+    fn log_notification(&mut self, notification: &DBNotification) -> Result<(), StoreError> {
+        let notifications = &mut self.find_mut(notification.chat_id)?.notifications;
+        notifications.push(notification.clone());
+        if notifications.len() > NOTIFICATIONS_MAX {
+            notifications.drain(..notifications.len() - NOTIFICATIONS_MAX);
+        }
+        self.save()
+    }
+
+    fn notifications(&self, chat_id: i64, limit: usize) -> Result<Vec<DBNotification>, StoreError> {
+        Ok(self.users.iter().find(|u| u.user.chat_id == chat_id)
+            .map(|u| u.notifications.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::muus::MuusListingKind;
+    use crate::testutil::{dtime, listing, temp_dir};
+
+    /// Opens both stores in a fresh directory, reopened when called again with the same `name`.
+    fn stores(name: &str, reset: bool) -> Vec<Box<dyn Store>> {
+        let dir = temp_dir(&format!("store_{name}"), reset);
+        vec![Box::new(JsonStore::open(&dir.join("db.json"), 1).unwrap()),
+             Box::new(SqliteStore::open(&dir.join("db.sqlite")).unwrap())]
+    }
+
+    fn user(chat_id: i64) -> DBUser {
+        DBUser { chat_id, user_id: format!("user{chat_id}"), queries: Vec::new(), timezone: DEFAULT_TIMEZONE }
+    }
+
+    fn seen(id: u64, secs: i64) -> DBSeenListing {
+        DBSeenListing { id, dtime_seen: dtime(secs), title: format!("Listing {id}"), price: Some(100),
+                        text_hash: Some(u64::MAX - id) }
+    }
+
+    fn notification(chat_id: i64, listing_id: u64) -> DBNotification {
+        DBNotification { chat_id, uniq_id: 0, listing_id, title: format!("Listing {listing_id}"),
+                         url: format!("https://muusikoiden.net/tori/ilmoitus/{listing_id}"), edited: false,
+                         dtime_sent: dtime(listing_id as i64) }
+    }
+
+    #[test]
+    fn users() {
+        for mut store in stores("users", true) {
+            assert_eq!(store.users().unwrap(), vec![]);
+            assert_eq!(store.user(1).unwrap(), None);
+
+            store.put_user(&user(1)).unwrap();
+            store.put_user(&user(-100)).unwrap();
+            assert_eq!(store.user(1).unwrap(), Some(user(1)));
+            assert_eq!(store.users().unwrap().len(), 2);
+
+            // Queries are saved separately
+            let user_new = DBUser { timezone: chrono_tz::Europe::Stockholm, queries: vec![DBQuery::new(0, "moog")],
+                                    ..user(1) };
+            store.put_user(&user_new).unwrap();
+            assert_eq!(store.user(1).unwrap(), Some(DBUser { queries: Vec::new(), ..user_new }));
+
+            assert!(store.remove_user(1).unwrap());
+            assert!(!store.remove_user(1).unwrap());
+            assert_eq!(store.users().unwrap(), vec![user(-100)]);
+        }
+    }
+
+    #[test]
+    fn queries() {
+        for mut store in stores("queries", true) {
+            assert!(matches!(store.put_query(1, &DBQuery::new(0, "moog")), Err(StoreError::UnknownUser(1))));
+            store.put_user(&user(1)).unwrap();
+            store.put_user(&user(2)).unwrap();
+
+            let q_full = DBQuery {
+                dtime_prev_req: Some(dtime(0)),
+                notify_edits: false,
+                filters: MuusFilters { kind: Some(MuusListingKind::Selling), price_max: Some(300),
+                                       ..MuusFilters::default() },
+                local_filter: MuusLocalFilter { exclude: vec!["rikki".to_string()], title_regex: Some("mk ?2".to_string()),
+                                                ..MuusLocalFilter::default() },
+                paused: true,
+                interval: Some(900),
+                imported: true,
+                ..DBQuery::new(1, "arturia+keylab")
+            };
+            store.put_query(1, &DBQuery::new(0, "moog")).unwrap();
+            store.put_query(1, &q_full).unwrap();
+            store.put_query(2, &DBQuery::new(0, "moog")).unwrap();
+            assert_eq!(store.user(1).unwrap().unwrap().queries, vec![DBQuery::new(0, "moog"), q_full.clone()]);
+
+            // Updates keep the seen listings
+            assert!(store.save_run(1, 1, dtime(60), &[seen(7, 0), seen(8, 60)]).unwrap());
+            store.put_query(1, &DBQuery { paused: false, ..q_full.clone() }).unwrap();
+            let q = store.user(1).unwrap().unwrap().queries.remove(1);
+            assert_eq!(q, DBQuery { paused: false, dtime_prev_req: Some(dtime(0)),
+                                    seen: vec![seen(7, 0), seen(8, 60)], ..q_full });
+
+            assert!(store.remove_query(1, 0).unwrap());
+            assert!(!store.remove_query(1, 0).unwrap());
+            assert_eq!(store.user(1).unwrap().unwrap().queries.len(), 1);
+            assert_eq!(store.user(2).unwrap().unwrap().queries, vec![DBQuery::new(0, "moog")]);
+        }
+    }
+
+    #[test]
+    fn seen_listings() {
+        for mut store in stores("seen", true) {
+            assert!(!store.save_run(1, 0, dtime(0), &[]).unwrap());
+            store.put_user(&user(1)).unwrap();
+            store.put_query(1, &DBQuery { imported: true, ..DBQuery::new(0, "moog") }).unwrap();
+            assert!(!store.save_run(1, 1, dtime(0), &[]).unwrap());
+
+            let no_state = DBSeenListing { title: String::new(), price: None, text_hash: None, ..seen(3, 0) };
+            assert!(store.save_run(1, 0, dtime(0), &[seen(1, 0), seen(2, 0), no_state.clone()]).unwrap());
+            assert!(store.save_run(1, 0, dtime(60), &[seen(2, 60), no_state.clone()]).unwrap());
+            let q = store.user(1).unwrap().unwrap().queries.remove(0);
+            assert_eq!(q.dtime_prev_req, Some(dtime(60)));
+            assert_eq!(q.seen, vec![seen(2, 60), no_state]);
+            assert!(!q.imported);
+
+            // Removed along with the query
+            assert!(store.remove_query(1, 0).unwrap());
+            store.put_query(1, &DBQuery::new(0, "moog")).unwrap();
+            assert_eq!(store.user(1).unwrap().unwrap().queries, vec![DBQuery::new(0, "moog")]);
+        }
+    }
+
+    #[test]
+    fn seen_state_backfilled() {
+        // As recorded before the listing state was
+        let mut q = DBQuery { seen: vec![DBSeenListing { title: String::new(), price: None, text_hash: None, ..seen(1, 0) }],
+                              ..DBQuery::new(0, "moog") };
+        let changed = MuusListing { price: Some(400), text_hash: 11, ..listing(1) };
+        assert!(q.take_news(vec![changed.clone()], dtime(3600)).is_empty());
+        assert_eq!(q.seen[0].text_hash, Some(11));
+        assert_eq!((q.seen[0].title.as_str(), q.seen[0].price), ("Listing 1", Some(400)));
+
+        let news = q.take_news(vec![MuusListing { text_hash: 12, ..changed }], dtime(7200));
+        assert!(matches!(news.as_slice(), [ListingNews::Edited { price_prev: Some(400), .. }]));
+    }
+
+    #[test]
+    fn seen_pruned() {
+        let mut q = DBQuery::new(0, "moog");
+        let day = 86400;
+        let dtime_now = dtime(SEEN_LISTINGS_TTL * day);
+
+        // Only the entries seen within the TTL are kept
+        q.seen = vec![seen(1, 0), seen(2, 1), seen(3, SEEN_LISTINGS_TTL * day)];
+        q.prune_seen(dtime_now);
+        let ids: Vec<u64> = q.seen.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        // The most recently seen ones are kept, whatever the order
+        q.seen = (0..SEEN_LISTINGS_MAX as u64 + 5).rev().map(|id| seen(id, day + id as i64)).collect();
+        q.prune_seen(dtime_now);
+        assert_eq!(q.seen.len(), SEEN_LISTINGS_MAX);
+        assert_eq!(q.seen.iter().map(|s| s.id).min(), Some(5));
+    }
+
+    #[test]
+    fn notification_log() {
+        for mut store in stores("notifications", true) {
+            assert!(store.log_notification(&notification(1, 1)).is_err());
+            store.put_user(&user(1)).unwrap();
+            store.put_user(&user(2)).unwrap();
+            for id in 0..NOTIFICATIONS_MAX as u64 + 5 {
+                store.log_notification(&notification(1, id)).unwrap();
+            }
+            store.log_notification(&DBNotification { edited: true, ..notification(2, 1) }).unwrap();
+
+            let log = store.notifications(1, 3).unwrap();
+            assert_eq!(log, vec![notification(1, 104), notification(1, 103), notification(1, 102)]);
+            assert_eq!(store.notifications(1, 1000).unwrap().len(), NOTIFICATIONS_MAX);
+            assert_eq!(store.notifications(2, 10).unwrap(), vec![DBNotification { edited: true, ..notification(2, 1) }]);
+
+            assert!(store.remove_user(1).unwrap());
+            assert_eq!(store.notifications(1, 10).unwrap(), vec![]);
+        }
+    }
+
+    #[test]
+    fn json_migrations() {
+        let dir = temp_dir("store_migrations", true);
+        let path = dir.join("db.json");
+
+        // As written before the versioning, and before the seen listings by the first query
+        let db_v0 = r#"[{"chat_id": 1, "user_id": "user1",
+                         "queries": [{"uniq_id": 0, "text": "moog", "dtime_prev_req": "2023-11-14T22:13:20Z"},
+                                     {"uniq_id": 1, "text": "korg", "dtime_prev_req": "2023-11-14T22:13:20Z",
+                                      "seen": []},
+                                     {"uniq_id": 2, "text": "nord", "dtime_prev_req": null}]}]"#;
+        fs::write(&path, db_v0).unwrap();
+        let store = JsonStore::open(&path, 1).unwrap();
+        let q = DBQuery { dtime_prev_req: Some(dtime(0)), ..DBQuery::new(0, "moog") };
+        let queries = vec![DBQuery { imported: true, ..q.clone() }, DBQuery { uniq_id: 1, text: "korg".to_string(), ..q },
+                           DBQuery::new(2, "nord")];
+        assert_eq!(store.users().unwrap(), vec![DBUser { queries, ..user(1) }]);
+        let read = |path: PathBuf| serde_json::from_str::<Value>(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(read(dir.join("db.json.schema-v0")), serde_json::from_str::<Value>(db_v0).unwrap());
+
+        let db: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(db["schema_version"], SCHEMA_VERSION);
+        assert_eq!(db["users"][0]["queries"][0]["text"], "moog");
+
+        // Only a backup is left, e.g. after a crash or a corrupt file
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(dir.join("db.json.schema-v0")).unwrap();
+        fs::write(dir.join("db.json.1"), db_v0).unwrap();
+        assert_eq!(JsonStore::open(&path, 1).unwrap().users().unwrap(), store.users().unwrap());
+        assert_eq!(read(dir.join("db.json.schema-v0")), serde_json::from_str::<Value>(db_v0).unwrap());
+        assert_eq!(read(path.clone())["schema_version"], SCHEMA_VERSION);
+
+        // Already current, not backed up again
+        fs::remove_file(dir.join("db.json.schema-v0")).unwrap();
+        assert_eq!(JsonStore::open(&path, 1).unwrap().users().unwrap(), store.users().unwrap());
+        assert!(!dir.join("db.json.schema-v0").exists());
+
+        // The first run of a legacy query does not report the listings from before its last one
+        let mut store = JsonStore::open(&path, 1).unwrap();
+        let ms = crate::due_searches(&store, &Config::default(), dtime(3600)).remove(0);
+        let listings = vec![MuusListing { dtime_add: dtime(60), dtime_upd: dtime(60), ..listing(2) },
+                            MuusListing { dtime_add: dtime(-60), dtime_upd: dtime(-60), ..listing(1) }];
+        let (news, _) = crate::apply_results(&mut store, &ms, listings).unwrap().unwrap();
+        assert!(matches!(news.as_slice(), [ListingNews::New(l)] if l.id == 2));
+        assert!(!store.user(1).unwrap().unwrap().queries[0].imported);
+
+        fs::write(&path, r#"{"schema_version": 99, "users": []}"#).unwrap();
+        assert!(matches!(JsonStore::open(&path, 0), Err(StoreError::Schema(_))));
+        fs::write(&path, r#"{"users": []}"#).unwrap();
+        assert!(matches!(JsonStore::open(&path, 0), Err(StoreError::Schema(_))));
+    }
+
+    #[test]
+    fn json_backups_spaced() {
+        let dir = temp_dir("store_backups", true);
+        let path = dir.join("db.json");
+        let n_users = |path: PathBuf| serde_json::from_str::<Value>(&fs::read_to_string(path).unwrap()).unwrap()
+            ["users"].as_array().unwrap().len();
+
+        let mut store = JsonStore::open(&path, 2).unwrap();
+        store.put_user(&user(1)).unwrap();
+        store.put_user(&user(2)).unwrap();
+        // Not rotated again within the interval
+        store.put_user(&user(3)).unwrap();
+        assert_eq!((n_users(path.clone()), n_users(dir.join("db.json.1"))), (3, 1));
+        assert!(!dir.join("db.json.2").exists());
+
+        store.dtime_backup = Instant::now().checked_sub(BACKUP_INTERVAL);
+        store.put_user(&user(4)).unwrap();
+        assert_eq!((n_users(dir.join("db.json.1")), n_users(dir.join("db.json.2"))), (3, 1));
+    }
+
+    #[test]
+    fn sqlite_migrations() {
+        let dir = temp_dir("store_sqlite_migrations", true);
+        let path = dir.join("db.sqlite");
+
+        // Version 1 had no imported flag
+        let mut store = SqliteStore::open(&path).unwrap();
+        store.put_user(&user(1)).unwrap();
+        store.put_query(1, &DBQuery::new(0, "moog")).unwrap();
+        drop(store);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("ALTER TABLE queries DROP COLUMN imported; PRAGMA user_version = 1;").unwrap();
+        drop(conn);
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.user(1).unwrap().unwrap().queries, vec![DBQuery::new(0, "moog")]);
+        drop(store);
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("PRAGMA user_version = 99;").unwrap();
+        drop(conn);
+        assert!(matches!(SqliteStore::open(&path), Err(StoreError::Schema(_))));
+    }
+
+    #[test]
+    fn reopen() {
+        for mut store in stores("reopen", true) {
+            store.put_user(&user(1)).unwrap();
+            store.put_query(1, &DBQuery::new(0, "moog")).unwrap();
+            store.save_run(1, 0, dtime(0), &[seen(1, 0)]).unwrap();
+            store.log_notification(&notification(1, 1)).unwrap();
+        }
+        for store in stores("reopen", false) {
+            let user = store.user(1).unwrap().unwrap();
+            assert_eq!(user.queries[0].seen, vec![seen(1, 0)]);
+            assert_eq!(store.notifications(1, 10).unwrap(), vec![notification(1, 1)]);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Helpers shared by the tests of all the modules.
+
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use chrono::prelude::*;
+use crate::muus::MuusListing;
+
+
+/// Directory of the test `name`, unique to the process. Emptied first if `reset`, else kept to reopen.
+pub(crate) fn temp_dir(name: &str, reset: bool) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("muus_{}_{name}", std::process::id()));
+    if reset {
+        _ = fs::remove_dir_all(&dir);
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Time `secs` after a fixed point, 2023-11-14 22:13:20 UTC.
+pub(crate) fn dtime(secs: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(1_700_000_000 + secs, 0).unwrap()
+}
+
+/// Contents of the file in `tests/fixtures/`.
+pub(crate) fn fixture(name: &str) -> String {
+    let path = format!("{}/tests/fixtures/{name}", env!("CARGO_MANIFEST_DIR"));
+    fs::read_to_string(&path).unwrap_or_else(|_| panic!("missing fixture {path}"))
+}
+
+/// A listing with only the required fields, added at `dtime(0)`.
+pub(crate) fn listing(id: u64) -> MuusListing {
+    MuusListing {
+        id,
+        title: format!("Listing {id}"),
+        url: format!("https://muusikoiden.net/tori/ilmoitus/{id}"),
+        kind: None,
+        price: None,
+        region: None,
+        seller: None,
+        text_hash: id,
+        dtime_add: dtime(0),
+        dtime_upd: dtime(0),
+    }
+}
+
+/// Mock marketplace: answers each request with the status and the HTML body returned by `handler`
+/// for the request URL and the request number. Returns the base URL of the server.
+pub(crate) fn serve_with(handler: impl Fn(&str, usize) -> (u16, String) + Send + 'static) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", server.server_addr().to_ip().unwrap());
+    thread::spawn(move || {
+        for (n, request) in server.incoming_requests().enumerate() {
+            let (status, body) = handler(request.url(), n);
+            let header = tiny_http::Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap();
+            _ = request.respond(tiny_http::Response::from_string(body).with_status_code(status).with_header(header));
+        }
+    });
+    base_url
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::io::Read;
+use std::net::SocketAddr;
+use frankenstein::Update;
+use tiny_http::{Request, Response, Server};
+
+
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";  // set by Telegram to the `secret_token` of the webhook
+const BODY_MAX: u64 = 1 << 20;  // in bytes, longer requests are rejected unread
+
+/// HTTP server receiving the updates that Telegram pushes to the webhook of the bot.
+pub(crate) struct WebhookServer {
+    server: Server,
+    path: String,
+    secret: String,
+}
+
+impl WebhookServer {
+    /// Listens on `addr` for POST requests to `path` that carry `secret` in the secret token header.
+    pub fn bind(addr: &str, path: &str, secret: &str) -> Result<WebhookServer, Box<dyn Error + Send + Sync>> {
+        Ok(WebhookServer {
+            server: Server::http(addr)?,
+            path: path.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+
+    /// Actual address of the server, e.g. with the port chosen by the OS for "127.0.0.1:0".
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Passes each received update to `handle`, in the order of arrival, never returns.
+    ///
+    /// Telegram gets the response before the update is handled, so that a slow command
+    /// does not make it resend the update.
+    pub fn serve(&self, mut handle: impl FnMut(Update)) {
+        for mut request in self.server.incoming_requests() {
+            let (status, update) = self.check(&mut request);
+            if let Err(err) = request.respond(Response::empty(status)) {
+                println!("Failed to respond to webhook request: {err}");
+            }
+            if let Some(update) = update {
+                handle(update);
+            }
+        }
+    }
+
+    /// Status of the response to the request, and the update in it if it is accepted.
+    fn check(&self, request: &mut Request) -> (u16, Option<Update>) {
+        if request.url() != self.path {
+            return (404, None);
+        }
+        if *request.method() != tiny_http::Method::Post {
+            return (405, None);
+        }
+        let secret = request.headers().iter()
+            .find(|h| h.field.equiv(SECRET_HEADER))
+            .map(|h| h.value.as_str());
+        if secret != Some(self.secret.as_str()) {
+            println!("Rejected webhook request from {:?}: wrong secret token", request.remote_addr());
+            return (401, None);
+        }
+        if request.body_length().is_some_and(|len| len as u64 > BODY_MAX) {
+            return (413, None);
+        }
+
+        let mut body = String::new();
+        if request.as_reader().take(BODY_MAX).read_to_string(&mut body).is_err() {
+            return (400, None);
+        }
+        let value: serde_json::Value = match serde_json::from_str(&body) {
+            Ok(value) => value,
+            Err(_) => return (400, None),
+        };
+        match serde_json::from_value::<Update>(value) {
+            Ok(update) => (200, Some(update)),
+            Err(err) => {
+                // Telegram would keep resending an update that is refused
+                println!("Ignoring unsupported update: {err}");
+                (200, None)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use frankenstein::UpdateContent;
+    use crate::testutil::fixture;
+
+    #[test]
+    fn recorded_updates() {
+        let server = WebhookServer::bind("127.0.0.1:0", "/telegram", "s3cret").unwrap();
+        let url = format!("http://{}/telegram", server.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || server.serve(|update| tx.send(update).unwrap()));
+
+        let client = reqwest::blocking::Client::new();
+        let post = |url: &str, secret: &str, body: String| {
+            client.post(url).header(SECRET_HEADER, secret).body(body).send().unwrap().status().as_u16()
+        };
+
+        assert_eq!(post(&url, "s3cret", fixture("update_message.json")), 200);
+        let update = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(update.update_id, 100001);
+        let UpdateContent::Message(message) = update.content else { panic!("not a message: {update:?}") };
+        assert_eq!(message.text.as_deref(), Some("/add moog sub 37"));
+
+        assert_eq!(post(&url, "s3cret", fixture("update_callback.json")), 200);
+        let update = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(update.content, UpdateContent::CallbackQuery(_)));
+
+        // Refused without reaching the handler
+        assert_eq!(post(&url, "wrong", fixture("update_message.json")), 401);
+        assert_eq!(post(&url.replace("/telegram", "/other"), "s3cret", fixture("update_message.json")), 404);
+        assert_eq!(client.get(&url).header(SECRET_HEADER, "s3cret").send().unwrap().status().as_u16(), 405);
+        assert_eq!(post(&url, "s3cret", "{\"update_id\": ".to_string()), 400);
+        // Accepted so that Telegram does not resend it, but not handled
+        assert_eq!(post(&url, "s3cret", "{\"update_id\": 100003, \"poll\": {}}".to_string()), 200);
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}